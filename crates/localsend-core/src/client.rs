@@ -0,0 +1,374 @@
+use std::{
+    collections::HashMap,
+    io,
+    net::SocketAddr,
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::Duration,
+};
+
+use futures::TryStreamExt;
+use reqwest::{Body, ClientBuilder, StatusCode};
+use tokio::fs::File;
+use tokio_util::io::ReaderStream;
+use tracing::{info, trace, warn};
+use uuid::Uuid;
+
+use crate::{
+    tls::PinningVerifier, DeviceInfo, Error, FileInfo, FileType, KnownPeers, Protocol, Result,
+    SendMessage, SendRequest, Sender, SessionError,
+};
+
+// urls can't carry the scope id of link-local IPv6 addresses, requests to those go to this
+// placeholder host which the http client resolves to the full address
+const SCOPED_HOST: &str = "scoped-peer.localsend";
+// registering is part of discovery, a peer that doesn't answer quickly gets a multicast reply
+const REGISTER_TIMEOUT: Duration = Duration::from_secs(2);
+
+pub struct Client {
+    http_client: reqwest::Client,
+    this_device: DeviceInfo,
+    protocol: Protocol,
+    known_peers: Option<Arc<KnownPeers>>,
+}
+
+// http client used for a single peer, pinned to its certificate if we know its fingerprint
+struct PeerConnection {
+    http_client: reqwest::Client,
+    verifier: Option<Arc<PinningVerifier>>,
+}
+
+impl PeerConnection {
+    fn map_err(&self, err: reqwest::Error) -> Error {
+        self.verifier
+            .as_ref()
+            .and_then(|verifier| verifier.take_mismatch())
+            .unwrap_or(Error::Http(err))
+    }
+}
+
+impl Client {
+    pub fn new(device_alias: String, fingerprint: String, port: u16) -> Result<Self> {
+        let http_client = unpinned_client_builder().build()?;
+
+        let this_device = DeviceInfo::this_device(device_alias, fingerprint, port);
+
+        Ok(Self {
+            http_client,
+            this_device,
+            protocol: Protocol::Https,
+            known_peers: None,
+        })
+    }
+
+    /// Talks to peers over `protocol` instead of https, peers with encryption turned off only
+    /// serve plain http.
+    pub fn with_protocol(mut self, protocol: Protocol) -> Self {
+        self.protocol = protocol;
+        self.this_device.protocol = protocol;
+        self
+    }
+
+    /// Pins the certificates of peers in `known_peers` on first use and refuses to talk to peers
+    /// presenting a different certificate later on.
+    pub fn with_known_peers(mut self, known_peers: Arc<KnownPeers>) -> Self {
+        self.known_peers = Some(known_peers);
+        self
+    }
+
+    /// Asks the peer listening on `addr` who it is.
+    pub async fn fetch_info(&self, addr: SocketAddr) -> Result<DeviceInfo> {
+        let http_client = self.http_client_for(addr)?;
+        let mut response = http_client.get(self.url(addr, "v2/info")).send().await?;
+        // v1 peers only know about the v1 route
+        if response.status() == StatusCode::NOT_FOUND {
+            response = http_client.get(self.url(addr, "v1/info")).send().await?;
+        }
+
+        match response.status() {
+            StatusCode::OK => {
+                let mut device_info: DeviceInfo = response.json().await?;
+                device_info.set_addr(addr);
+                device_info.port = addr.port();
+                Ok(device_info)
+            }
+            status => Err(SessionError::UnexpectedStatus(status).into()),
+        }
+    }
+
+    /// Tells the peer listening on `addr` about this device, in reply to its announcement.
+    /// `protocol` is the one announced by the peer. Returns the info of the peer.
+    pub async fn register(&self, addr: SocketAddr, protocol: Protocol) -> Result<DeviceInfo> {
+        let response = self
+            .http_client_for(addr)?
+            .post(api_url(protocol, addr, "v2/register"))
+            .timeout(REGISTER_TIMEOUT)
+            .json(&self.this_device)
+            .send()
+            .await?;
+
+        match response.status() {
+            StatusCode::OK => {
+                let mut device_info: DeviceInfo = response.json().await?;
+                device_info.set_addr(addr);
+                device_info.port = addr.port();
+                Ok(device_info)
+            }
+            status => Err(SessionError::UnexpectedStatus(status).into()),
+        }
+    }
+
+    async fn connect(&self, addr: SocketAddr) -> Result<PeerConnection> {
+        let unpinned_connection = PeerConnection {
+            http_client: self.http_client_for(addr)?,
+            verifier: None,
+        };
+        let Some(known_peers) = self.known_peers.clone() else {
+            return Ok(unpinned_connection);
+        };
+        // there is no certificate to pin without tls
+        if self.protocol == Protocol::Http {
+            return Ok(unpinned_connection);
+        }
+
+        let peer = self.fetch_info(addr).await?;
+        // the info request isn't pinned, don't let it swap the fingerprint of a pinned peer
+        if let Some(pinned_fingerprint) = known_peers.pinned_fingerprint(addr.ip()) {
+            if pinned_fingerprint != peer.fingerprint {
+                return Err(Error::AddressMismatch {
+                    addr: addr.ip().to_canonical(),
+                    pinned_fingerprint,
+                    fingerprint: peer.fingerprint,
+                });
+            }
+        }
+        if peer.fingerprint.is_empty() {
+            warn!(
+                "{} didn't send a fingerprint, can't pin its certificate",
+                addr
+            );
+            return Ok(unpinned_connection);
+        }
+
+        let verifier = Arc::new(PinningVerifier::new(
+            known_peers,
+            peer.fingerprint,
+            peer.alias,
+            addr.ip(),
+        ));
+        let http_client = resolve_scoped(reqwest::Client::builder(), addr)
+            .use_preconfigured_tls(verifier.clone().client_config())
+            .build()?;
+        Ok(PeerConnection {
+            http_client,
+            verifier: Some(verifier),
+        })
+    }
+
+    /// Sends `paths` to the peer listening on `addr`, returns once every accepted file has been
+    /// uploaded. Progress is reported over `client_tx` if given.
+    pub async fn send_files(
+        &self,
+        addr: SocketAddr,
+        paths: &[PathBuf],
+        client_tx: Option<Sender<SendMessage>>,
+    ) -> Result<()> {
+        let (send_request, file_paths) = self.build_send_request(paths).await?;
+        let connection = self.connect(addr).await?;
+        let tokens = self.send_request(&connection, addr, &send_request).await?;
+        info!("{} accepted {} file(s)", addr, tokens.len());
+
+        if let Some(client_tx) = client_tx.as_ref() {
+            let accepted_files = tokens
+                .keys()
+                .filter_map(|file_id| send_request.files.get(file_id).cloned())
+                .collect();
+            let _ = client_tx.send(SendMessage::Accepted(accepted_files));
+        }
+
+        for (file_id, token) in tokens.iter() {
+            let Some(path) = file_paths.get(file_id) else {
+                // the receiver answered with an id we never offered
+                continue;
+            };
+
+            let result = self
+                .send_file(&connection, addr, file_id, token, path, client_tx.as_ref())
+                .await;
+
+            if let Some(client_tx) = client_tx.as_ref() {
+                let _ = client_tx.send(match result {
+                    Ok(()) => SendMessage::FileFinished(file_id.clone()),
+                    Err(_) => SendMessage::FileFailed(file_id.clone()),
+                });
+            }
+            if let Err(err) = result {
+                // the receiver would otherwise wait for the remaining files forever
+                self.cancel(&connection, addr).await;
+                return Err(err);
+            }
+        }
+        Ok(())
+    }
+
+    pub async fn build_send_request(
+        &self,
+        paths: &[PathBuf],
+    ) -> Result<(SendRequest, HashMap<String, PathBuf>)> {
+        let mut files = HashMap::new();
+        let mut file_paths = HashMap::new();
+
+        for path in paths {
+            let metadata = tokio::fs::metadata(path).await?;
+            if !metadata.is_file() {
+                return Err(Error::Io(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    format!("{} is not a file", path.display()),
+                )));
+            }
+
+            let file_id = Uuid::new_v4().to_string();
+            let file_info = FileInfo {
+                id: file_id.clone(),
+                size: metadata.len() as usize,
+                file_name: file_name(path),
+                file_type: FileType::from_path(path),
+                sha256: None,
+                preview: None,
+            };
+            files.insert(file_id.clone(), file_info);
+            file_paths.insert(file_id, path.clone());
+        }
+
+        let send_request = SendRequest {
+            device_info: self.this_device.clone(),
+            files,
+        };
+        Ok((send_request, file_paths))
+    }
+
+    async fn send_request(
+        &self,
+        connection: &PeerConnection,
+        addr: SocketAddr,
+        send_request: &SendRequest,
+    ) -> Result<HashMap<String, String>> {
+        let url = self.url(addr, "v1/send-request");
+        trace!("POST {}", url);
+
+        let response = connection
+            .http_client
+            .post(url)
+            .json(send_request)
+            .send()
+            .await
+            .map_err(|err| connection.map_err(err))?;
+
+        match response.status() {
+            StatusCode::OK => Ok(response.json().await?),
+            StatusCode::FORBIDDEN => Err(SessionError::Declined.into()),
+            StatusCode::CONFLICT => Err(SessionError::Blocked.into()),
+            status => Err(SessionError::UnexpectedStatus(status).into()),
+        }
+    }
+
+    async fn send_file(
+        &self,
+        connection: &PeerConnection,
+        addr: SocketAddr,
+        file_id: &str,
+        token: &str,
+        path: &Path,
+        client_tx: Option<&Sender<SendMessage>>,
+    ) -> Result<()> {
+        let url = self.url(addr, "v1/send");
+        trace!("POST {} {}", url, path.display());
+
+        let file = File::open(path).await?;
+        let progress_tx = client_tx.cloned();
+        let progress_file_id = file_id.to_string();
+        let file_stream = ReaderStream::new(file).inspect_ok(move |chunk| {
+            if let Some(progress_tx) = progress_tx.as_ref() {
+                let _ = progress_tx.send(SendMessage::SendFileProgress((
+                    progress_file_id.clone(),
+                    chunk.len(),
+                )));
+            }
+        });
+
+        let response = connection
+            .http_client
+            .post(url)
+            .query(&[("fileId", file_id), ("token", token)])
+            .body(Body::wrap_stream(file_stream))
+            .send()
+            .await
+            .map_err(|err| connection.map_err(err))?;
+
+        match response.status() {
+            StatusCode::OK => Ok(()),
+            StatusCode::FORBIDDEN => Err(SessionError::Forbidden.into()),
+            status => Err(SessionError::UnexpectedStatus(status).into()),
+        }
+    }
+
+    // best effort, the upload error is what gets reported
+    async fn cancel(&self, connection: &PeerConnection, addr: SocketAddr) {
+        let url = self.url(addr, "v1/cancel");
+        trace!("POST {}", url);
+
+        let result = connection.http_client.post(url).send().await;
+        match result.map(|response| response.status()) {
+            Ok(StatusCode::OK) => {}
+            Ok(status) => warn!("cancelling the session with {} failed: {}", addr, status),
+            Err(err) => warn!("cancelling the session with {} failed: {}", addr, err),
+        }
+    }
+
+    fn url(&self, addr: SocketAddr, route: &str) -> String {
+        api_url(self.protocol, addr, route)
+    }
+
+    fn http_client_for(&self, addr: SocketAddr) -> Result<reqwest::Client> {
+        if !has_scope_id(addr) {
+            return Ok(self.http_client.clone());
+        }
+        Ok(resolve_scoped(unpinned_client_builder(), addr).build()?)
+    }
+}
+
+fn unpinned_client_builder() -> ClientBuilder {
+    // peers use self signed certificates, so there is no CA we could verify against
+    reqwest::Client::builder().danger_accept_invalid_certs(true)
+}
+
+fn has_scope_id(addr: SocketAddr) -> bool {
+    matches!(addr, SocketAddr::V6(addr) if addr.scope_id() != 0)
+}
+
+fn resolve_scoped(builder: ClientBuilder, addr: SocketAddr) -> ClientBuilder {
+    if has_scope_id(addr) {
+        builder.resolve(SCOPED_HOST, addr)
+    } else {
+        builder
+    }
+}
+
+fn api_url(protocol: Protocol, addr: SocketAddr, route: &str) -> String {
+    if has_scope_id(addr) {
+        return format!(
+            "{}://{}:{}/api/localsend/{}",
+            protocol.scheme(),
+            SCOPED_HOST,
+            addr.port(),
+            route
+        );
+    }
+    format!("{}://{}/api/localsend/{}", protocol.scheme(), addr, route)
+}
+
+fn file_name(path: &Path) -> String {
+    path.file_name()
+        .map(|file_name| file_name.to_string_lossy().into_owned())
+        .unwrap_or_else(|| path.to_string_lossy().into_owned())
+}
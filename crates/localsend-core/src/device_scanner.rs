@@ -1,26 +1,157 @@
 use std::{
-    net::{IpAddr, Ipv4Addr},
-    sync::Arc,
-    time::Duration,
+    collections::HashMap,
+    io,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+    time::{Duration, Instant},
 };
 
-use tokio::net::UdpSocket;
-use tracing::debug;
-use uuid::Uuid;
+use futures::{FutureExt, StreamExt};
+use socket2::{Domain, InterfaceIndexOrAddress, SockRef, Socket, Type};
+use tokio::{net::UdpSocket, task::JoinHandle};
+use tracing::{debug, warn};
 
+use crate::BUFFER_SIZE;
 use crate::{
-    protos::{DeviceInfo, DeviceResponse},
-    utils::get_device_ip_addr,
-    NUM_REPEAT,
+    list_interfaces,
+    protos::{DeviceInfo, DeviceResponse, Protocol},
+    utils::get_local_ip_addrs,
+    Client, Error, InterfaceFilter, NetworkInterface, NetworkWatcher, PeerRegistry, Result,
+    StaticPeers, SubnetSweeper, SweepConfig, NUM_REPEAT,
 };
-use crate::{BUFFER_SIZE, DEVICE_MODEL, DEVICE_TYPE};
+
+const MAX_ALIAS_LEN: usize = 256;
+const MAX_FINGERPRINT_LEN: usize = 128;
+// replies to the same source are sent at most once per interval, an announcement is repeated
+// NUM_REPEAT times so this also collapses those into a single reply
+const REPLY_INTERVAL: Duration = Duration::from_secs(2);
+// upper bound on replies across all sources so a storm of announcements can't make us flood
+// the multicast group
+const MAX_REPLIES_PER_INTERVAL: usize = 16;
+
+// sockets sending multicast out of a single interface
+type SendSockets = Vec<(NetworkInterface, Arc<UdpSocket>)>;
+
+/// Counters for packets received by the [`DeviceScanner`].
+#[derive(Debug, Default)]
+pub struct ScannerStats {
+    received: AtomicU64,
+    dropped_malformed: AtomicU64,
+    dropped_oversized: AtomicU64,
+    dropped_spoofed: AtomicU64,
+    replies_rate_limited: AtomicU64,
+}
+
+impl ScannerStats {
+    pub fn received(&self) -> u64 {
+        self.received.load(Ordering::Relaxed)
+    }
+
+    pub fn dropped_malformed(&self) -> u64 {
+        self.dropped_malformed.load(Ordering::Relaxed)
+    }
+
+    pub fn dropped_oversized(&self) -> u64 {
+        self.dropped_oversized.load(Ordering::Relaxed)
+    }
+
+    pub fn dropped_spoofed(&self) -> u64 {
+        self.dropped_spoofed.load(Ordering::Relaxed)
+    }
+
+    pub fn replies_rate_limited(&self) -> u64 {
+        self.replies_rate_limited.load(Ordering::Relaxed)
+    }
+
+    fn increment(counter: &AtomicU64) {
+        counter.fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+/// Parses and validates an announcement received over multicast.
+pub fn parse_announcement(packet: &[u8]) -> Result<DeviceResponse> {
+    if packet.len() > BUFFER_SIZE as usize {
+        return Err(Error::InvalidPacket(
+            "packet is larger than the buffer size",
+        ));
+    }
+
+    let device_response: DeviceResponse = serde_json::from_slice(packet)?;
+    validate_device_info(&device_response.device_info)?;
+    Ok(device_response)
+}
+
+// checks the fields we key peers by and show to the user, shared with register requests
+pub(crate) fn validate_device_info(device_info: &DeviceInfo) -> Result<()> {
+    if device_info.fingerprint.is_empty() || device_info.fingerprint.len() > MAX_FINGERPRINT_LEN {
+        return Err(Error::InvalidPacket("invalid fingerprint"));
+    }
+    if device_info.alias.len() > MAX_ALIAS_LEN {
+        return Err(Error::InvalidPacket("alias is too long"));
+    }
+    Ok(())
+}
+
+// keeps track of when we last replied to a source
+struct ReplyLimiter {
+    last_replies: HashMap<IpAddr, Instant>,
+}
+
+impl ReplyLimiter {
+    fn new() -> Self {
+        Self {
+            last_replies: HashMap::new(),
+        }
+    }
+
+    fn should_reply(&mut self, src: IpAddr) -> bool {
+        let now = Instant::now();
+        self.last_replies
+            .retain(|_, last_reply| now.duration_since(*last_reply) < REPLY_INTERVAL);
+
+        if self.last_replies.contains_key(&src)
+            || self.last_replies.len() >= MAX_REPLIES_PER_INTERVAL
+        {
+            return false;
+        }
+        self.last_replies.insert(src, now);
+        true
+    }
+}
+
+// multicast groups joined by the scanner and the tasks announcing on them, redone whenever the
+// addresses of the interfaces change
+#[derive(Default)]
+struct Membership {
+    send_sockets: SendSockets,
+    socket_v6: Option<Arc<UdpSocket>>,
+    // the os picked the IPv4 interface
+    joined_default_v4: bool,
+    announce_tasks: Vec<JoinHandle<()>>,
+}
+
+impl Drop for Membership {
+    fn drop(&mut self) {
+        for announce_task in self.announce_tasks.iter() {
+            announce_task.abort();
+        }
+    }
+}
 
 pub struct DeviceScanner {
     pub socket: Arc<UdpSocket>,
     this_device: DeviceResponse,
-    devices: Vec<DeviceInfo>,
+    stats: Arc<ScannerStats>,
+    registry: PeerRegistry,
     interface_addr: Ipv4Addr,
+    filter: InterfaceFilter,
+    interfaces: Vec<NetworkInterface>,
+    network_watcher: Option<NetworkWatcher>,
     multicast_addr: Ipv4Addr,
+    multicast_addr_v6: Option<Ipv6Addr>,
     multicast_port: u16,
 }
 
@@ -28,114 +159,653 @@ impl DeviceScanner {
     // TODO(notjedi): is it a good idea for a new func o be async
     pub async fn new(
         device_alias: String,
+        fingerprint: String,
         interface_addr: Ipv4Addr,
         multicast_addr: Ipv4Addr,
         multicast_port: u16,
-    ) -> Self {
+    ) -> Result<Self> {
+        let addr = SocketAddr::from((interface_addr, multicast_port));
         let socket = Arc::new(
-            UdpSocket::bind((interface_addr, multicast_port))
+            UdpSocket::bind(addr)
                 .await
-                .expect("couldn't bind to address"),
+                .map_err(|source| Error::Bind { addr, source })?,
         );
-        let fingerprint = Uuid::new_v4();
-        let ip_addr = get_device_ip_addr().unwrap_or(IpAddr::V4([0, 0, 0, 0].into()));
-
-        let device_info = DeviceInfo {
-            alias: device_alias,
-            device_type: DEVICE_TYPE.to_string(),
-            device_model: Some(DEVICE_MODEL.to_string()),
-            ip: ip_addr.to_string(),
-            port: multicast_port,
-        };
-        let this_device = DeviceResponse {
-            device_info,
-            announcement: true,
-            fingerprint: fingerprint.to_string(),
-        };
+        let device_info = DeviceInfo::this_device(device_alias, fingerprint, multicast_port);
+        let mut this_device = DeviceResponse::from(device_info);
+        this_device.set_announcement(true);
 
-        Self {
+        Ok(Self {
             socket,
             this_device,
-            devices: vec![],
+            stats: Arc::new(ScannerStats::default()),
+            registry: PeerRegistry::default(),
             interface_addr,
+            filter: InterfaceFilter::default(),
+            interfaces: vec![],
+            network_watcher: None,
             multicast_addr,
+            multicast_addr_v6: None,
             multicast_port,
         }
+        .with_interface_filter(InterfaceFilter::default()))
+    }
+
+    /// Picks the interfaces discovery runs on when the scanner is bound to the unspecified
+    /// address, by default every interface except those of containers and VMs.
+    pub fn with_interface_filter(mut self, filter: InterfaceFilter) -> Self {
+        self.filter = filter;
+        self.refresh_interfaces();
+        self
+    }
+
+    /// Rejoins the multicast groups and announces again whenever `watcher` sees the addresses of
+    /// the interfaces change, instead of sticking to the ones found at startup.
+    pub fn with_network_watcher(mut self, watcher: NetworkWatcher) -> Self {
+        self.network_watcher = Some(watcher);
+        self
+    }
+
+    /// Keeps discovered devices in `registry` instead of one with the default ttl, e.g. one
+    /// created with [`PeerRegistry::new`] to match the sweep interval. Sweepers and static peers
+    /// created afterwards feed it too.
+    pub fn with_registry(mut self, registry: PeerRegistry) -> Self {
+        self.registry = registry;
+        self
+    }
+
+    fn refresh_interfaces(&mut self) {
+        self.interfaces = if self.interface_addr.is_unspecified() {
+            list_interfaces(&self.filter)
+        } else {
+            list_interfaces(&InterfaceFilter::all())
+                .into_iter()
+                .filter(|interface| interface.addr == IpAddr::V4(self.interface_addr))
+                .collect()
+        };
+        let addrs = || self.interfaces.iter().map(|interface| interface.addr);
+        self.this_device.device_info.ip = addrs()
+            .find(IpAddr::is_ipv4)
+            .or_else(|| addrs().next())
+            .unwrap_or(Ipv4Addr::UNSPECIFIED.into());
+    }
+
+    /// Also discovers peers over IPv6, on the link-local multicast `group` of every interface
+    /// with an IPv6 address.
+    pub fn with_ipv6_multicast(mut self, group: Ipv6Addr) -> Self {
+        self.multicast_addr_v6 = Some(group);
+        self
+    }
+
+    /// Sets the protocol announced to peers, it has to match the one the server is serving.
+    pub fn with_protocol(mut self, protocol: Protocol) -> Self {
+        self.this_device.device_info.protocol = protocol;
+        self
+    }
+
+    /// Interfaces the scanner joins the multicast group and announces on.
+    pub fn interfaces(&self) -> &[NetworkInterface] {
+        &self.interfaces
+    }
+
+    pub fn this_device(&self) -> &DeviceInfo {
+        &self.this_device.device_info
+    }
+
+    pub fn stats(&self) -> Arc<ScannerStats> {
+        self.stats.clone()
+    }
+
+    /// Devices discovered by this scanner, shared with the returned registry.
+    pub fn registry(&self) -> PeerRegistry {
+        self.registry.clone()
+    }
+
+    /// Creates a sweeper that probes the local subnets for devices, for networks that drop
+    /// multicast. Found devices end up in the registry of this scanner.
+    pub fn subnet_sweeper(&self, config: SweepConfig) -> Result<SubnetSweeper> {
+        let device_info = &self.this_device.device_info;
+        Ok(SubnetSweeper::new(
+            self.client()?,
+            self.registry.clone(),
+            self.interfaces.clone(),
+            self.multicast_port,
+            device_info.protocol,
+            config,
+        ))
+    }
+
+    /// Creates a prober for `peers`, given as `host` or `host:port`, for devices on networks no
+    /// discovery reaches. They end up in the registry of this scanner.
+    pub fn static_peers(&self, peers: Vec<String>) -> Result<StaticPeers> {
+        Ok(StaticPeers::new(
+            self.client()?,
+            self.registry.clone(),
+            peers,
+            self.multicast_port,
+        ))
+    }
+
+    fn client(&self) -> Result<Client> {
+        let device_info = &self.this_device.device_info;
+        Ok(Client::new(
+            device_info.alias.clone(),
+            device_info.fingerprint.clone(),
+            device_info.port,
+        )?
+        .with_protocol(device_info.protocol))
     }
 
     pub async fn announce(
         send_socket: &Arc<UdpSocket>,
         announcement_msg: &str,
-        addr: (Ipv4Addr, u16),
-    ) {
+        addr: SocketAddr,
+    ) -> Result<()> {
         // TODO(notjedi): any other way to not accept addr as argument
         send_socket
             .send_to(announcement_msg.as_bytes(), addr)
-            .await
-            .unwrap();
+            .await?;
+        Ok(())
     }
 
     pub async fn announce_repeat(
         send_socket: Arc<UdpSocket>,
         announcement_msg: String,
-        addr: (Ipv4Addr, u16),
+        addr: SocketAddr,
     ) {
         // TODO(notjedi): any other way to not accept addr as argument
         loop {
             for _ in 0..NUM_REPEAT {
-                Self::announce(&send_socket, announcement_msg.as_str(), addr).await;
+                if let Err(err) =
+                    Self::announce(&send_socket, announcement_msg.as_str(), addr).await
+                {
+                    warn!("failed to announce: {}", err);
+                }
             }
             tokio::time::sleep(Duration::from_secs(5)).await;
         }
     }
 
-    pub async fn listen_and_announce_multicast(&mut self) {
+    // replies to an announcement by registering with the announcer like the reference
+    // implementation does, v1 peers and peers we can't reach get a multicast reply instead
+    async fn reply(
+        client: Arc<Client>,
+        send_socket: Option<Arc<UdpSocket>>,
+        reply_announce_msg: String,
+        peer: DeviceInfo,
+        peer_addr: SocketAddr,
+        multicast_addr: SocketAddr,
+    ) {
+        if peer.version.is_some() {
+            match client.register(peer_addr, peer.protocol).await {
+                Ok(_) => return,
+                Err(err) => debug!(
+                    "failed to register with {}, replying over multicast: {}",
+                    peer_addr, err
+                ),
+            }
+        }
+
+        let Some(send_socket) = send_socket else {
+            debug!("no interface to reply to {} on", peer_addr);
+            return;
+        };
+        if let Err(err) =
+            Self::announce(&send_socket, reply_announce_msg.as_str(), multicast_addr).await
+        {
+            warn!("failed to reply to announcement: {}", err);
+        }
+    }
+
+    // joins the multicast group on every IPv4 interface and returns a socket per interface to
+    // send announcements from, falls back to letting the os pick an interface if that fails
+    fn join_multicast_v4(&self) -> Result<SendSockets> {
+        let mut send_sockets = vec![];
+        for interface in self.interfaces.iter() {
+            let IpAddr::V4(interface_addr) = interface.addr else {
+                continue;
+            };
+            if let Err(err) = self
+                .socket
+                .join_multicast_v4(self.multicast_addr, interface_addr)
+            {
+                warn!(
+                    "failed to join multicast group on {} ({}): {}",
+                    interface.name, interface.addr, err
+                );
+                continue;
+            }
+
+            match multicast_send_socket(interface_addr) {
+                Ok(send_socket) => send_sockets.push((interface.clone(), Arc::new(send_socket))),
+                Err(err) => warn!(
+                    "failed to create send socket on {} ({}): {}",
+                    interface.name, interface.addr, err
+                ),
+            }
+        }
+
+        if send_sockets.is_empty() {
+            self.socket
+                .join_multicast_v4(self.multicast_addr, self.interface_addr)
+                .map_err(|source| Error::MulticastJoin {
+                    group: self.multicast_addr.into(),
+                    source,
+                })?;
+        }
+        Ok(send_sockets)
+    }
+
+    // binds a socket to the IPv6 group on every IPv6 interface and returns it with a socket per
+    // interface to send announcements from. link-local groups exist once per interface, so this
+    // goes by interface index rather than address
+    fn join_multicast_v6(&self, group: Ipv6Addr) -> Result<(Arc<UdpSocket>, SendSockets)> {
+        let addr = SocketAddr::from((Ipv6Addr::UNSPECIFIED, self.multicast_port));
+        let socket =
+            multicast_recv_socket_v6(addr).map_err(|source| Error::Bind { addr, source })?;
+
+        let mut send_sockets: SendSockets = vec![];
+        for interface in self
+            .interfaces
+            .iter()
+            .filter(|interface| interface.addr.is_ipv6())
+        {
+            if send_sockets
+                .iter()
+                .any(|(joined_interface, _)| joined_interface.index == interface.index)
+            {
+                continue;
+            }
+            if let Err(err) = socket.join_multicast_v6(&group, interface.index) {
+                warn!(
+                    "failed to join multicast group on {} ({}): {}",
+                    interface.name, interface.addr, err
+                );
+                continue;
+            }
+
+            match multicast_send_socket_v6(interface.index) {
+                Ok(send_socket) => send_sockets.push((interface.clone(), Arc::new(send_socket))),
+                Err(err) => warn!(
+                    "failed to create send socket on {} ({}): {}",
+                    interface.name, interface.addr, err
+                ),
+            }
+        }
+
+        if send_sockets.is_empty() {
+            return Err(Error::MulticastJoin {
+                group: group.into(),
+                source: io::Error::new(io::ErrorKind::NotFound, "no usable IPv6 interface"),
+            });
+        }
+        Ok((Arc::new(socket), send_sockets))
+    }
+
+    // joins the multicast groups on the current interfaces and starts announcing on them
+    fn join(&self, announce_msg: &str) -> Result<Membership> {
+        let mut membership = Membership::default();
+        let mut announce_sockets = vec![];
+        let group_v4 = SocketAddr::from((self.multicast_addr, self.multicast_port));
+        let group_v6 = self
+            .multicast_addr_v6
+            .map(|group| SocketAddr::from((group, self.multicast_port)));
+
+        if let Some(group) = self.multicast_addr_v6 {
+            match self.join_multicast_v6(group) {
+                Ok((socket_v6, sockets_v6)) => {
+                    membership.send_sockets.extend(sockets_v6);
+                    membership.socket_v6 = Some(socket_v6);
+                }
+                Err(err) => warn!("not discovering over IPv6: {}", err),
+            }
+        }
+        match self.join_multicast_v4() {
+            // let the os pick the interface
+            Ok(sockets_v4) if sockets_v4.is_empty() => {
+                membership.joined_default_v4 = true;
+                announce_sockets.push((self.socket.clone(), group_v4))
+            }
+            Ok(sockets_v4) => membership.send_sockets.extend(sockets_v4),
+            // IPv6 only networks
+            Err(err) if membership.socket_v6.is_some() => {
+                warn!("not discovering over IPv4: {}", err)
+            }
+            Err(err) => return Err(err),
+        }
+        for (interface, send_socket) in membership.send_sockets.iter() {
+            let group = match interface.addr {
+                IpAddr::V4(_) => group_v4,
+                IpAddr::V6(_) => group_v6.unwrap_or(group_v4),
+            };
+            announce_sockets.push((send_socket.clone(), group));
+        }
+
+        membership.announce_tasks = announce_sockets
+            .into_iter()
+            .map(|(send_socket, group)| {
+                tokio::spawn(Self::announce_repeat(
+                    send_socket,
+                    announce_msg.to_string(),
+                    group,
+                ))
+            })
+            .collect();
+        Ok(membership)
+    }
+
+    // leaves the groups of `membership` and joins them again on the interfaces we have now
+    fn rejoin(&mut self, membership: &mut Membership, announce_msg: &str) {
+        // stops the announcements and closes the IPv6 socket so its port can be bound again
+        let joined_default_v4 = std::mem::take(membership).joined_default_v4;
+        let socket = SockRef::from(self.socket.as_ref());
+        let mut indexes: Vec<_> = self
+            .interfaces
+            .iter()
+            .filter(|interface| interface.addr.is_ipv4())
+            .map(|interface| interface.index)
+            .collect();
+        indexes.dedup();
+        for index in indexes {
+            // the address we joined with may be gone, the index of the interface outlives it
+            let _ = socket
+                .leave_multicast_v4_n(&self.multicast_addr, &InterfaceIndexOrAddress::Index(index));
+        }
+        if joined_default_v4 {
+            let _ = socket.leave_multicast_v4(&self.multicast_addr, &self.interface_addr);
+        }
+
+        self.refresh_interfaces();
+        match self.join(announce_msg) {
+            Ok(new_membership) => *membership = new_membership,
+            Err(err) => warn!("not discovering until the network changes again: {}", err),
+        }
+    }
+
+    pub async fn listen_and_announce_multicast(&mut self) -> Result<()> {
         // https://gist.github.com/pusateri/df98511b88e9000f388d344a1f3db9e7
-        self.socket
-            .join_multicast_v4(self.multicast_addr, self.interface_addr)
-            .expect("failed to join multicast");
-
-        self.this_device.announcement = true;
-        let send_socket = self.socket.clone();
-        let announce_msg = serde_json::to_string(&self.this_device).unwrap();
-        tokio::spawn(Self::announce_repeat(
-            send_socket,
-            announce_msg,
-            (self.multicast_addr, self.multicast_port),
-        ));
+        let group_v4 = SocketAddr::from((self.multicast_addr, self.multicast_port));
+        let group_v6 = self
+            .multicast_addr_v6
+            .map(|group| SocketAddr::from((group, self.multicast_port)));
 
-        self.this_device.announcement = false;
-        let reply_announce_msg = serde_json::to_string(&self.this_device).unwrap();
+        self.this_device.set_announcement(true);
+        let announce_msg = serde_json::to_string(&self.this_device)?;
+        let mut membership = self.join(&announce_msg)?;
 
-        let mut buf = [0u8; BUFFER_SIZE as usize];
+        self.this_device.set_announcement(false);
+        let reply_announce_msg = serde_json::to_string(&self.this_device)?;
+        let client = Arc::new(self.client()?);
+        let mut network_events = match self.network_watcher.as_ref() {
+            Some(network_watcher) => network_watcher.events().boxed(),
+            None => futures::stream::pending().boxed(),
+        };
+
+        let mut local_ip_addrs = get_local_ip_addrs();
+        let mut reply_limiter = ReplyLimiter::new();
+
+        // one byte larger than the largest packet we accept, so truncated packets can be told apart
+        let mut buf = [0u8; BUFFER_SIZE as usize + 1];
+        let mut buf_v6 = [0u8; BUFFER_SIZE as usize + 1];
         loop {
-            if let Ok((amt, src)) = self.socket.recv_from(&mut buf).await {
-                let mut device_response: DeviceResponse =
-                    serde_json::from_slice(&buf[..amt]).unwrap();
-                (
-                    device_response.device_info.ip,
-                    device_response.device_info.port,
-                ) = (src.ip().to_string(), src.port());
-
-                if device_response == self.this_device {
+            let received = tokio::select! {
+                received = self.socket.recv_from(&mut buf) => received,
+                received = recv_from(membership.socket_v6.as_deref(), &mut buf_v6) => received,
+                Some(event) = network_events.next() => {
+                    debug!("network changed: {:?}", event);
+                    // a network coming up changes several addresses at once
+                    while let Some(Some(_)) = network_events.next().now_or_never() {}
+                    self.rejoin(&mut membership, &announce_msg);
+                    local_ip_addrs = get_local_ip_addrs();
+                    continue;
+                }
+            };
+            let Ok((amt, src)) = received else {
+                continue;
+            };
+            // the IPv6 socket is v6 only, so the family tells which buffer the packet is in
+            let packet = if src.is_ipv6() {
+                &buf_v6[..amt]
+            } else {
+                &buf[..amt]
+            };
+            ScannerStats::increment(&self.stats.received);
+
+            let mut device_response = match parse_announcement(packet) {
+                Ok(device_response) => device_response,
+                Err(err) => {
+                    debug!("dropping packet from {}: {}", src, err);
+                    if amt > BUFFER_SIZE as usize {
+                        ScannerStats::increment(&self.stats.dropped_oversized);
+                    } else {
+                        ScannerStats::increment(&self.stats.dropped_malformed);
+                    }
                     continue;
                 }
+            };
 
-                if device_response.announcement {
-                    Self::announce(
-                        &self.socket,
-                        reply_announce_msg.as_str(),
-                        (self.multicast_addr, self.multicast_port),
-                    )
-                    .await;
+            if device_response == self.this_device {
+                // our own announcements are looped back to us, anyone else using our
+                // fingerprint is impersonating us
+                if !src.ip().is_loopback() && !local_ip_addrs.contains(&src.ip()) {
+                    debug!("dropping packet from {} using our fingerprint", src);
+                    ScannerStats::increment(&self.stats.dropped_spoofed);
                 }
+                continue;
+            }
+            if src.port() == 0 || src.ip().is_unspecified() || src.ip().is_multicast() {
+                debug!("dropping packet from invalid source {}", src);
+                ScannerStats::increment(&self.stats.dropped_spoofed);
+                continue;
+            }
+
+            device_response.device_info.set_addr(src);
+            // v1 peers don't announce the port their server is listening on
+            if device_response.device_info.port == 0 {
+                device_response.device_info.port = src.port();
+            }
 
-                if !self.devices.contains(&device_response.device_info) {
-                    self.devices.push(device_response.device_info);
-                    debug!("{:#?}", &self.devices);
-                    debug!("{:#?}", &self.devices.len());
+            if device_response.is_announcement() {
+                if reply_limiter.should_reply(src.ip()) {
+                    let send_socket = send_socket_for(&membership.send_sockets, src)
+                        .or_else(|| src.is_ipv4().then(|| self.socket.clone()));
+                    let group = match src {
+                        SocketAddr::V4(_) => group_v4,
+                        SocketAddr::V6(_) => group_v6.unwrap_or(group_v4),
+                    };
+                    // keeps the scope id of link-local peers
+                    let mut peer_addr = src;
+                    peer_addr.set_port(device_response.device_info.port);
+                    tokio::spawn(Self::reply(
+                        client.clone(),
+                        send_socket,
+                        reply_announce_msg.clone(),
+                        device_response.device_info.clone(),
+                        peer_addr,
+                        group,
+                    ));
+                } else {
+                    ScannerStats::increment(&self.stats.replies_rate_limited);
                 }
             }
+
+            self.registry.upsert(device_response.device_info);
         }
     }
 }
+
+// socket sending multicast out of a single interface, with the address of the interface as source
+fn multicast_send_socket(interface_addr: Ipv4Addr) -> io::Result<UdpSocket> {
+    let socket = Socket::new(Domain::IPV4, Type::DGRAM, None)?;
+    socket.set_multicast_if_v4(&interface_addr)?;
+    socket.bind(&SocketAddr::from((interface_addr, 0)).into())?;
+    socket.set_nonblocking(true)?;
+    UdpSocket::from_std(socket.into())
+}
+
+// socket sending multicast out of the IPv6 interface with the given index
+fn multicast_send_socket_v6(interface_index: u32) -> io::Result<UdpSocket> {
+    let socket = Socket::new(Domain::IPV6, Type::DGRAM, None)?;
+    socket.set_only_v6(true)?;
+    socket.set_multicast_if_v6(interface_index)?;
+    socket.bind(&SocketAddr::from((Ipv6Addr::UNSPECIFIED, 0)).into())?;
+    socket.set_nonblocking(true)?;
+    UdpSocket::from_std(socket.into())
+}
+
+// v6 only so it can share the port with the IPv4 socket
+fn multicast_recv_socket_v6(addr: SocketAddr) -> io::Result<UdpSocket> {
+    let socket = Socket::new(Domain::IPV6, Type::DGRAM, None)?;
+    socket.set_only_v6(true)?;
+    socket.bind(&addr.into())?;
+    socket.set_nonblocking(true)?;
+    UdpSocket::from_std(socket.into())
+}
+
+// waits forever without a socket, so the IPv6 socket can always be selected on
+async fn recv_from(socket: Option<&UdpSocket>, buf: &mut [u8]) -> io::Result<(usize, SocketAddr)> {
+    match socket {
+        Some(socket) => socket.recv_from(buf).await,
+        None => std::future::pending().await,
+    }
+}
+
+// the socket of the interface `src` is on, replies sent from it reach the peer from an address
+// it can reach back
+fn send_socket_for(
+    send_sockets: &[(NetworkInterface, Arc<UdpSocket>)],
+    src: SocketAddr,
+) -> Option<Arc<UdpSocket>> {
+    send_sockets
+        .iter()
+        .find(|(interface, _)| match src {
+            SocketAddr::V6(src) if src.scope_id() != 0 => {
+                interface.addr.is_ipv6() && interface.index == src.scope_id()
+            }
+            _ => interface.contains(src.ip()),
+        })
+        .map(|(_, send_socket)| send_socket.clone())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // xorshift, enough to get varied garbage without pulling in a crate for it
+    fn random_bytes(seed: &mut u64, len: usize) -> Vec<u8> {
+        (0..len)
+            .map(|_| {
+                *seed ^= *seed << 13;
+                *seed ^= *seed >> 7;
+                *seed ^= *seed << 17;
+                *seed as u8
+            })
+            .collect()
+    }
+
+    fn announcement(alias: &str, fingerprint: &str) -> Vec<u8> {
+        let mut device_response = DeviceResponse::from(DeviceInfo {
+            alias: alias.into(),
+            fingerprint: fingerprint.into(),
+            ..Default::default()
+        });
+        device_response.set_announcement(true);
+        serde_json::to_vec(&device_response).unwrap()
+    }
+
+    #[test]
+    fn parses_a_valid_announcement() {
+        let device_response = parse_announcement(&announcement("peer", "fingerprint")).unwrap();
+        assert_eq!(device_response.device_info.alias, "peer");
+        assert!(device_response.is_announcement());
+    }
+
+    #[test]
+    fn random_bytes_are_rejected() {
+        let mut seed = 0x2545_f491_4f6c_dd1d;
+        for len in 0..=BUFFER_SIZE as usize + 64 {
+            let packet = random_bytes(&mut seed, len);
+            assert!(parse_announcement(&packet).is_err());
+        }
+    }
+
+    #[test]
+    fn truncated_announcements_are_rejected() {
+        let packet = announcement("peer", "fingerprint");
+        for len in 0..packet.len() {
+            assert!(parse_announcement(&packet[..len]).is_err());
+        }
+    }
+
+    #[test]
+    fn oversized_packets_are_rejected() {
+        let mut packet = announcement("peer", "fingerprint");
+        packet.resize(BUFFER_SIZE as usize, b' ');
+        assert!(parse_announcement(&packet).is_ok());
+        packet.push(b' ');
+        assert!(matches!(
+            parse_announcement(&packet),
+            Err(Error::InvalidPacket(_))
+        ));
+    }
+
+    #[test]
+    fn oversized_fields_are_rejected() {
+        let alias = "a".repeat(MAX_ALIAS_LEN);
+        assert!(parse_announcement(&announcement(&alias, "fingerprint")).is_ok());
+        let alias = "a".repeat(MAX_ALIAS_LEN + 1);
+        assert!(parse_announcement(&announcement(&alias, "fingerprint")).is_err());
+
+        let fingerprint = "f".repeat(MAX_FINGERPRINT_LEN);
+        assert!(parse_announcement(&announcement("peer", &fingerprint)).is_ok());
+        let fingerprint = "f".repeat(MAX_FINGERPRINT_LEN + 1);
+        assert!(parse_announcement(&announcement("peer", &fingerprint)).is_err());
+        assert!(parse_announcement(&announcement("peer", "")).is_err());
+    }
+
+    #[test]
+    fn replies_are_capped_per_source() {
+        let mut limiter = ReplyLimiter::new();
+        let src = IpAddr::from([192, 168, 1, 2]);
+        assert!(limiter.should_reply(src));
+        for _ in 0..NUM_REPEAT {
+            assert!(!limiter.should_reply(src));
+        }
+        assert!(limiter.should_reply(IpAddr::from([192, 168, 1, 3])));
+
+        // the source can be replied to again once the interval passed
+        let replied_at = Instant::now().checked_sub(REPLY_INTERVAL).unwrap();
+        limiter.last_replies.insert(src, replied_at);
+        assert!(limiter.should_reply(src));
+    }
+
+    #[test]
+    fn replies_are_capped_overall() {
+        let mut limiter = ReplyLimiter::new();
+        for host in 0..MAX_REPLIES_PER_INTERVAL as u8 {
+            assert!(limiter.should_reply(IpAddr::from([10, 0, 0, host])));
+        }
+        assert!(!limiter.should_reply(IpAddr::from([10, 0, 1, 0])));
+    }
+
+    #[tokio::test]
+    async fn discovered_devices_go_to_the_given_registry() {
+        let registry = PeerRegistry::new(Duration::from_secs(120)).unwrap();
+        let scanner = DeviceScanner::new(
+            "scanner".into(),
+            "scanner-fingerprint".into(),
+            Ipv4Addr::LOCALHOST,
+            Ipv4Addr::new(224, 0, 0, 167),
+            0,
+        )
+        .await
+        .unwrap()
+        .with_registry(registry.clone());
+
+        scanner.registry().upsert(DeviceInfo {
+            alias: "peer".into(),
+            fingerprint: "peer-fingerprint".into(),
+            ..Default::default()
+        });
+        assert!(registry.get("peer-fingerprint").is_some());
+    }
+}
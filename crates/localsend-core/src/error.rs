@@ -0,0 +1,65 @@
+use std::{
+    io,
+    net::{IpAddr, SocketAddr},
+};
+
+use reqwest::StatusCode;
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+#[derive(Debug, thiserror::Error)]
+pub enum Error {
+    #[error("failed to bind to {addr}: {source}")]
+    Bind { addr: SocketAddr, source: io::Error },
+    #[error("failed to join multicast group {group}: {source}")]
+    MulticastJoin { group: IpAddr, source: io::Error },
+    #[error("tls error: {0}")]
+    Tls(String),
+    #[error(
+        "certificate of {fingerprint} hashes to {cert_hash}, the device doesn't own the \
+         fingerprint it claims"
+    )]
+    FingerprintMismatch {
+        fingerprint: String,
+        cert_hash: String,
+    },
+    #[error(
+        "{addr} is pinned as {pinned_fingerprint} but now claims to be {fingerprint:?}. someone \
+         could be impersonating the device, forget it if it was replaced"
+    )]
+    AddressMismatch {
+        addr: IpAddr,
+        pinned_fingerprint: String,
+        fingerprint: String,
+    },
+    #[error("failed to parse message: {0}")]
+    Protocol(#[from] serde_json::Error),
+    #[error("invalid packet: {0}")]
+    InvalidPacket(&'static str),
+    #[error("invalid config: {0}")]
+    InvalidConfig(&'static str),
+    #[error("io error: {0}")]
+    Io(#[from] io::Error),
+    #[error("http error: {0}")]
+    Http(#[from] reqwest::Error),
+    #[error(transparent)]
+    Session(#[from] SessionError),
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum SessionError {
+    #[error("receiver declined the request")]
+    Declined,
+    #[error("receiver is blocked by another session")]
+    Blocked,
+    #[error("receiver rejected the token or ip address")]
+    Forbidden,
+    #[error("unexpected response status {0}")]
+    UnexpectedStatus(StatusCode),
+}
+
+impl From<rcgen::RcgenError> for Error {
+    fn from(err: rcgen::RcgenError) -> Self {
+        Self::Tls(err.to_string())
+    }
+}
@@ -0,0 +1,156 @@
+use std::{
+    fs,
+    io::{self, Write},
+    path::{Path, PathBuf},
+};
+
+use sha2::{Digest, Sha256};
+use tracing::info;
+
+use crate::{utils, Error, Result};
+
+const CERT_FILE_NAME: &str = "cert.pem";
+const KEY_FILE_NAME: &str = "key.pem";
+
+/// The TLS certificate of this device and the fingerprint derived from it.
+///
+/// The fingerprint is the SHA-256 of the certificate, like the reference implementation, so
+/// peers can tell whether a device presenting a fingerprint also owns the certificate.
+#[derive(Clone, Debug)]
+pub struct Identity {
+    cert_pem: String,
+    key_pem: String,
+    cert_der: Vec<u8>,
+    fingerprint: String,
+}
+
+impl Identity {
+    pub fn generate() -> Result<Self> {
+        let certificate = utils::generate_tls_cert()?;
+        Self::from_pem(
+            certificate.serialize_pem()?,
+            certificate.serialize_private_key_pem(),
+        )
+    }
+
+    pub fn from_pem(cert_pem: String, key_pem: String) -> Result<Self> {
+        let cert_der = rustls_pemfile::certs(&mut cert_pem.as_bytes())?
+            .into_iter()
+            .next()
+            .ok_or_else(|| Error::Tls("no certificate found in pem".into()))?;
+        let fingerprint = certificate_fingerprint(&cert_der);
+        Ok(Self {
+            cert_pem,
+            key_pem,
+            cert_der,
+            fingerprint,
+        })
+    }
+
+    /// Loads the identity stored in `state_dir`, generating and storing a new one on first run.
+    pub fn load_or_generate(state_dir: &Path) -> Result<Self> {
+        let cert_path = state_dir.join(CERT_FILE_NAME);
+        let key_path = state_dir.join(KEY_FILE_NAME);
+
+        if cert_path.exists() && key_path.exists() {
+            return Self::from_pem(
+                fs::read_to_string(cert_path)?,
+                fs::read_to_string(key_path)?,
+            );
+        }
+
+        let identity = Self::generate()?;
+        fs::create_dir_all(state_dir)?;
+        write_private_file(&key_path, identity.key_pem.as_bytes())?;
+        fs::write(&cert_path, identity.cert_pem.as_bytes())?;
+        info!("generated a new certificate in {}", state_dir.display());
+        Ok(identity)
+    }
+
+    pub fn default_state_dir() -> PathBuf {
+        dirs::state_dir()
+            .or_else(dirs::data_local_dir)
+            .unwrap_or_else(|| PathBuf::from("."))
+            .join("localsend-rs")
+    }
+
+    pub fn fingerprint(&self) -> &str {
+        &self.fingerprint
+    }
+
+    pub fn cert_pem(&self) -> &str {
+        &self.cert_pem
+    }
+
+    pub fn key_pem(&self) -> &str {
+        &self.key_pem
+    }
+
+    pub fn cert_der(&self) -> &[u8] {
+        &self.cert_der
+    }
+}
+
+pub fn certificate_fingerprint(cert_der: &[u8]) -> String {
+    Sha256::digest(cert_der)
+        .iter()
+        .map(|byte| format!("{:02x}", byte))
+        .collect()
+}
+
+fn write_private_file(path: &Path, contents: &[u8]) -> io::Result<()> {
+    let mut options = fs::OpenOptions::new();
+    options.write(true).create(true).truncate(true);
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::OpenOptionsExt;
+        options.mode(0o600);
+    }
+    options.open(path)?.write_all(contents)
+}
+
+#[cfg(test)]
+mod tests {
+    use uuid::Uuid;
+
+    use super::*;
+
+    fn state_dir() -> PathBuf {
+        std::env::temp_dir().join(Uuid::new_v4().to_string())
+    }
+
+    #[test]
+    fn loading_again_keeps_the_identity() {
+        let state_dir = state_dir();
+        let generated = Identity::load_or_generate(&state_dir).unwrap();
+        let loaded = Identity::load_or_generate(&state_dir).unwrap();
+        assert_eq!(loaded.fingerprint(), generated.fingerprint());
+        assert_eq!(loaded.cert_der(), generated.cert_der());
+    }
+
+    #[test]
+    fn fingerprint_is_the_sha256_of_the_certificate() {
+        let identity = Identity::generate().unwrap();
+        let hash = Sha256::digest(identity.cert_der());
+        assert_eq!(identity.fingerprint().len(), 64);
+        assert_eq!(identity.fingerprint(), hex(&hash));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn private_key_is_only_readable_by_the_owner() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let state_dir = state_dir();
+        Identity::load_or_generate(&state_dir).unwrap();
+        let mode = fs::metadata(state_dir.join(KEY_FILE_NAME))
+            .unwrap()
+            .permissions()
+            .mode();
+        assert_eq!(mode & 0o777, 0o600);
+    }
+
+    fn hex(bytes: &[u8]) -> String {
+        bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+    }
+}
@@ -0,0 +1,179 @@
+use std::{
+    collections::BTreeSet,
+    net::{IpAddr, Ipv4Addr},
+};
+
+use network_interface::{Addr, NetworkInterface as RawInterface, NetworkInterfaceConfig};
+
+use crate::utils;
+
+/// Shortest prefix [`subnet_hosts`] sweeps in full, whatever the configured limit.
+const MIN_PREFIX_LEN: u32 = 16;
+
+/// Prefixes of interfaces created by container and VM software, peers are never found there.
+const VIRTUAL_INTERFACE_PREFIXES: [&str; 10] = [
+    "docker", "br-", "veth", "virbr", "vmnet", "vboxnet", "lxcbr", "lxdbr", "podman", "cni",
+];
+
+/// An address of a network interface discovery runs on.
+#[derive(Clone, Debug, PartialEq)]
+pub struct NetworkInterface {
+    pub name: String,
+    pub index: u32,
+    pub addr: IpAddr,
+    pub netmask: Option<IpAddr>,
+}
+
+impl NetworkInterface {
+    /// Whether `ip` is on the subnet of this interface.
+    pub fn contains(&self, ip: IpAddr) -> bool {
+        match (self.addr, ip, self.netmask) {
+            (IpAddr::V4(addr), IpAddr::V4(ip), Some(IpAddr::V4(netmask))) => {
+                let mask = u32::from(netmask);
+                u32::from(ip) & mask == u32::from(addr) & mask
+            }
+            (IpAddr::V6(addr), IpAddr::V6(ip), Some(IpAddr::V6(netmask))) => {
+                let mask = u128::from(netmask);
+                u128::from(ip) & mask == u128::from(addr) & mask
+            }
+            _ => false,
+        }
+    }
+}
+
+/// Which interfaces discovery runs on, matched by name prefix.
+#[derive(Clone, Debug)]
+pub struct InterfaceFilter {
+    /// Only use interfaces starting with one of these, all interfaces if empty.
+    pub include: Vec<String>,
+    /// Never use interfaces starting with one of these, takes precedence over `include`.
+    pub exclude: Vec<String>,
+}
+
+impl Default for InterfaceFilter {
+    fn default() -> Self {
+        Self {
+            include: vec![],
+            exclude: VIRTUAL_INTERFACE_PREFIXES
+                .iter()
+                .map(|prefix| prefix.to_string())
+                .collect(),
+        }
+    }
+}
+
+impl InterfaceFilter {
+    /// Matches every interface, including those of containers and VMs.
+    pub fn all() -> Self {
+        Self {
+            include: vec![],
+            exclude: vec![],
+        }
+    }
+
+    pub fn matches(&self, name: &str) -> bool {
+        let starts_with = |prefix: &String| name.starts_with(prefix.as_str());
+        (self.include.is_empty() || self.include.iter().any(starts_with))
+            && !self.exclude.iter().any(starts_with)
+    }
+}
+
+/// Non loopback addresses of the interfaces of this device that pass `filter`.
+pub fn list_interfaces(filter: &InterfaceFilter) -> Vec<NetworkInterface> {
+    RawInterface::show()
+        .unwrap_or(vec![])
+        .into_iter()
+        .filter(|raw_interface| filter.matches(&raw_interface.name))
+        .flat_map(|raw_interface| {
+            raw_interface
+                .addr
+                .iter()
+                .filter_map(|addr| {
+                    let (addr, netmask): (IpAddr, _) = match addr {
+                        Addr::V4(addr) => (addr.ip.into(), addr.netmask.map(IpAddr::from)),
+                        Addr::V6(addr) => (addr.ip.into(), addr.netmask.map(IpAddr::from)),
+                    };
+                    (!addr.is_loopback()).then(|| NetworkInterface {
+                        name: raw_interface.name.clone(),
+                        index: raw_interface.index,
+                        addr,
+                        netmask,
+                    })
+                })
+                .collect::<Vec<_>>()
+        })
+        .collect()
+}
+
+/// Addresses of the other hosts on the IPv4 subnets of `interfaces`, IPv6 subnets are too large
+/// to sweep. Subnets larger than `max_prefix_len` are narrowed down to the /24 around our address,
+/// `max_prefix_len` itself is clamped to /16 - /32.
+pub(crate) fn subnet_hosts(interfaces: &[NetworkInterface], max_prefix_len: u32) -> Vec<Ipv4Addr> {
+    let max_prefix_len = max_prefix_len.clamp(MIN_PREFIX_LEN, 32);
+    let mut hosts = BTreeSet::new();
+    for interface in interfaces {
+        let IpAddr::V4(addr) = interface.addr else {
+            continue;
+        };
+        if addr.is_link_local() {
+            continue;
+        }
+        let prefix_len = match interface.netmask {
+            Some(IpAddr::V4(netmask)) => u32::from(netmask).leading_ones(),
+            _ => 24,
+        };
+        let prefix_len = match prefix_len {
+            prefix_len if prefix_len < max_prefix_len => 24,
+            prefix_len => prefix_len,
+        };
+        // point to point links don't have other hosts to scan
+        if prefix_len >= 31 {
+            continue;
+        }
+
+        let mask = u32::MAX.checked_shl(32 - prefix_len).unwrap_or(0);
+        let network = u32::from(addr) & mask;
+        let broadcast = network | !mask;
+        hosts.extend((network + 1..broadcast).map(Ipv4Addr::from));
+    }
+
+    let local_addrs = utils::get_local_ip_addrs();
+    hosts
+        .into_iter()
+        .filter(|host| !local_addrs.contains(&IpAddr::V4(*host)))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn interface(addr: Ipv4Addr, netmask: Ipv4Addr) -> NetworkInterface {
+        NetworkInterface {
+            name: "eth0".into(),
+            index: 1,
+            addr: addr.into(),
+            netmask: Some(netmask.into()),
+        }
+    }
+
+    #[test]
+    fn whole_address_space_is_narrowed_down() {
+        let interfaces = [interface(Ipv4Addr::new(10, 1, 2, 3), Ipv4Addr::UNSPECIFIED)];
+        for max_prefix_len in [0, 8, 16] {
+            let hosts = subnet_hosts(&interfaces, max_prefix_len);
+            assert_eq!(hosts.len(), 254, "max prefix len {}", max_prefix_len);
+            assert_eq!(hosts[0], Ipv4Addr::new(10, 1, 2, 1));
+        }
+    }
+
+    #[test]
+    fn subnets_are_swept_up_to_a_slash_16() {
+        let interfaces = [interface(
+            Ipv4Addr::new(10, 1, 2, 3),
+            Ipv4Addr::new(255, 255, 0, 0),
+        )];
+        assert_eq!(subnet_hosts(&interfaces, 0).len(), 65534);
+        assert_eq!(subnet_hosts(&interfaces, 22).len(), 254);
+    }
+}
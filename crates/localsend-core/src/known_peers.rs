@@ -0,0 +1,145 @@
+use std::{
+    collections::HashMap,
+    fs,
+    net::IpAddr,
+    path::{Path, PathBuf},
+    sync::Mutex,
+};
+
+use serde::{Deserialize, Serialize};
+use tracing::warn;
+
+use crate::Result;
+
+const KNOWN_PEERS_FILE_NAME: &str = "known_peers.json";
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct KnownPeer {
+    /// The SHA-256 of the peer's certificate, so pinning the fingerprint pins the certificate.
+    pub fingerprint: String,
+    pub alias: String,
+    /// Addresses the peer presented its certificate from.
+    #[serde(default)]
+    pub addresses: Vec<IpAddr>,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum PinStatus {
+    /// The peer presented the certificate we saw the first time we talked to it.
+    Trusted,
+    /// First time we talked to the peer, its certificate is trusted from now on.
+    TrustedOnFirstUse,
+}
+
+/// Certificates of peers we talked to before, pinned on first use and persisted in the state
+/// directory.
+///
+/// A fingerprint is the hash of the certificate, a peer presenting another certificate can't
+/// present the same fingerprint. What is pinned is which fingerprint an address belongs to, see
+/// [`pinned_fingerprint`](Self::pinned_fingerprint).
+pub struct KnownPeers {
+    path: PathBuf,
+    peers: Mutex<HashMap<String, KnownPeer>>,
+}
+
+impl KnownPeers {
+    pub fn load(state_dir: &Path) -> Result<Self> {
+        let path = state_dir.join(KNOWN_PEERS_FILE_NAME);
+        let peers = if path.exists() {
+            serde_json::from_str::<Vec<KnownPeer>>(&fs::read_to_string(&path)?)?
+        } else {
+            vec![]
+        };
+
+        Ok(Self {
+            path,
+            peers: Mutex::new(
+                peers
+                    .into_iter()
+                    .map(|peer| (peer.fingerprint.clone(), peer))
+                    .collect(),
+            ),
+        })
+    }
+
+    pub fn list(&self) -> Vec<KnownPeer> {
+        self.peers.lock().unwrap().values().cloned().collect()
+    }
+
+    /// Forgets the peer with the given fingerprint or alias so its next certificate is trusted
+    /// on first use again.
+    pub fn forget(&self, fingerprint_or_alias: &str) -> Result<Option<KnownPeer>> {
+        let mut peers = self.peers.lock().unwrap();
+        let fingerprint = peers
+            .values()
+            .find(|peer| {
+                peer.fingerprint == fingerprint_or_alias || peer.alias == fingerprint_or_alias
+            })
+            .map(|peer| peer.fingerprint.clone());
+
+        let Some(fingerprint) = fingerprint else {
+            return Ok(None);
+        };
+        let peer = peers.remove(&fingerprint);
+        self.save(&peers)?;
+        Ok(peer)
+    }
+
+    /// Fingerprint of the pinned peer last seen at `addr`.
+    ///
+    /// Fingerprints are read from the peer before its certificate can be checked, so a device
+    /// in the middle could claim a fingerprint we never saw to get its own certificate trusted
+    /// on first use. Addresses of pinned peers have to keep presenting the same fingerprint.
+    pub fn pinned_fingerprint(&self, addr: IpAddr) -> Option<String> {
+        let addr = addr.to_canonical();
+        self.peers
+            .lock()
+            .unwrap()
+            .values()
+            .find(|peer| peer.addresses.contains(&addr))
+            .map(|peer| peer.fingerprint.clone())
+    }
+
+    /// Trusts the peer with `fingerprint`, whose certificate has to be checked to hash to it,
+    /// pinning it if we haven't seen the peer before. `addr` is remembered for the peer, a known
+    /// peer reached at a new address is still trusted.
+    pub fn verify(&self, fingerprint: &str, alias: &str, addr: IpAddr) -> Result<PinStatus> {
+        let addr = addr.to_canonical();
+        let mut peers = self.peers.lock().unwrap();
+        match peers.get_mut(fingerprint) {
+            Some(peer) => {
+                if !peer.addresses.contains(&addr) {
+                    peer.addresses.push(addr);
+                    self.save(&peers)?;
+                }
+                Ok(PinStatus::Trusted)
+            }
+            None => {
+                warn!(
+                    "trusting certificate of {} ({}) on first use",
+                    alias, fingerprint
+                );
+                peers.insert(
+                    fingerprint.to_string(),
+                    KnownPeer {
+                        fingerprint: fingerprint.to_string(),
+                        alias: alias.to_string(),
+                        addresses: vec![addr],
+                    },
+                );
+                self.save(&peers)?;
+                Ok(PinStatus::TrustedOnFirstUse)
+            }
+        }
+    }
+
+    fn save(&self, peers: &HashMap<String, KnownPeer>) -> Result<()> {
+        if let Some(parent) = self.path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        let peers = peers.values().collect::<Vec<_>>();
+        fs::write(&self.path, serde_json::to_string_pretty(&peers)?)?;
+        Ok(())
+    }
+}
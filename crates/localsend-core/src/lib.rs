@@ -1,15 +1,41 @@
+pub mod client;
 pub mod device_scanner;
+pub mod error;
+pub mod identity;
+pub mod interfaces;
+pub mod known_peers;
+pub mod peers;
+pub mod policy;
 pub mod protos;
+pub mod receive_handler;
+pub mod sanitize;
 pub mod server;
+pub mod static_peers;
+pub mod sweep;
+mod tls;
 mod utils;
+pub mod watcher;
 
+pub use client::*;
 pub use device_scanner::*;
+pub use error::*;
+pub use identity::*;
+pub use interfaces::*;
+pub use known_peers::*;
+pub use peers::*;
+pub use policy::*;
 pub use protos::*;
+pub use receive_handler::*;
+pub use sanitize::*;
 pub use server::*;
+pub use static_peers::*;
+pub use sweep::*;
+pub use watcher::*;
 
 const BUFFER_SIZE: u16 = 2048;
 
 pub const NUM_REPEAT: u8 = 2;
 
 const DEVICE_MODEL: &str = "linux";
-const DEVICE_TYPE: &str = "desktop";
+const DEVICE_TYPE: DeviceType = DeviceType::Desktop;
+const PROTOCOL_VERSION: &str = "2.0";
@@ -0,0 +1,272 @@
+use std::{
+    collections::HashMap,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, RwLock,
+    },
+    time::{Duration, Instant},
+};
+
+use futures::Stream;
+use tokio::sync::broadcast;
+use tracing::debug;
+
+use crate::{DeviceInfo, Error, Result};
+
+/// How long a peer is kept around after we last heard from it.
+pub const DEFAULT_PEER_TTL: Duration = Duration::from_secs(30);
+const EVENT_CHANNEL_CAPACITY: usize = 64;
+
+#[derive(Clone, Debug)]
+pub struct Peer {
+    pub device_info: DeviceInfo,
+    pub last_seen: Instant,
+    /// Added by address rather than discovered, never expires.
+    pub is_static: bool,
+}
+
+#[derive(Clone, Debug)]
+pub enum DeviceEvent {
+    Discovered(Peer),
+    Updated(Peer),
+    Lost(Peer),
+}
+
+/// Devices discovered on the network, keyed by fingerprint.
+///
+/// Peers are expired in the background once the first one is added, whichever of discovery,
+/// subnet sweeps or the server adds it. Cloning the registry is cheap and all clones share the
+/// same peers.
+#[derive(Clone)]
+pub struct PeerRegistry {
+    peers: Arc<RwLock<HashMap<String, Peer>>>,
+    event_tx: broadcast::Sender<DeviceEvent>,
+    ttl: Duration,
+    expiry_started: Arc<AtomicBool>,
+}
+
+impl Default for PeerRegistry {
+    fn default() -> Self {
+        Self::with_ttl(DEFAULT_PEER_TTL)
+    }
+}
+
+impl PeerRegistry {
+    /// Fails if `ttl` is zero.
+    pub fn new(ttl: Duration) -> Result<Self> {
+        if ttl.is_zero() {
+            return Err(Error::InvalidConfig("peer ttl must not be zero"));
+        }
+        Ok(Self::with_ttl(ttl))
+    }
+
+    fn with_ttl(ttl: Duration) -> Self {
+        let (event_tx, _) = broadcast::channel(EVENT_CHANNEL_CAPACITY);
+        Self {
+            peers: Arc::new(RwLock::new(HashMap::new())),
+            event_tx,
+            ttl,
+            expiry_started: Arc::new(AtomicBool::new(false)),
+        }
+    }
+
+    /// Records that we heard from `device_info`, emitting `Discovered` for new peers and
+    /// `Updated` if anything but the last seen time changed.
+    ///
+    /// A known peer keeps its address until it expires, announcements of its fingerprint from
+    /// any other address are ignored until then.
+    pub fn upsert(&self, device_info: DeviceInfo) {
+        self.insert(device_info, false);
+    }
+
+    /// Like [`upsert`](Self::upsert), but the peer is marked static and never expires.
+    pub fn upsert_static(&self, device_info: DeviceInfo) {
+        self.insert(device_info, true);
+    }
+
+    fn insert(&self, device_info: DeviceInfo, is_static: bool) {
+        self.start_expiry();
+        let mut peers = self.peers.write().unwrap();
+        let mut peer = Peer {
+            device_info,
+            last_seen: Instant::now(),
+            is_static,
+        };
+        if let Some(known_peer) = peers.get(&peer.device_info.fingerprint) {
+            // fingerprints are only claimed, anyone could announce a known one to take over its
+            // address. dual stack peers are heard over both IPv4 and IPv6, the one we saw first
+            // is the one that counts
+            if known_peer.device_info.ip != peer.device_info.ip {
+                debug!(
+                    "ignoring {} announced from {}, known at {}",
+                    peer.device_info.fingerprint, peer.device_info.ip, known_peer.device_info.ip
+                );
+                return;
+            }
+            // static peers are also discovered if multicast happens to reach them
+            peer.is_static |= known_peer.is_static;
+        }
+
+        let event = match peers.get(&peer.device_info.fingerprint) {
+            None => Some(DeviceEvent::Discovered(peer.clone())),
+            Some(known_peer) if !is_same_device(&known_peer.device_info, &peer.device_info) => {
+                Some(DeviceEvent::Updated(peer.clone()))
+            }
+            Some(_) => None,
+        };
+        peers.insert(peer.device_info.fingerprint.clone(), peer);
+        drop(peers);
+
+        if let Some(event) = event {
+            debug!("{:?}", event);
+            let _ = self.event_tx.send(event);
+        }
+    }
+
+    pub fn remove(&self, fingerprint: &str) -> Option<Peer> {
+        let peer = self.peers.write().unwrap().remove(fingerprint)?;
+        let _ = self.event_tx.send(DeviceEvent::Lost(peer.clone()));
+        Some(peer)
+    }
+
+    pub fn get(&self, fingerprint: &str) -> Option<Peer> {
+        self.peers.read().unwrap().get(fingerprint).cloned()
+    }
+
+    pub fn peers(&self) -> Vec<Peer> {
+        self.peers.read().unwrap().values().cloned().collect()
+    }
+
+    /// Removes peers that haven't been seen for longer than the ttl, except static ones.
+    pub fn expire(&self) {
+        let now = Instant::now();
+        let mut lost_peers = vec![];
+        self.peers.write().unwrap().retain(|_, peer| {
+            let is_alive = peer.is_static || now.duration_since(peer.last_seen) < self.ttl;
+            if !is_alive {
+                lost_peers.push(peer.clone());
+            }
+            is_alive
+        });
+
+        for peer in lost_peers {
+            debug!("lost {:?}", peer);
+            let _ = self.event_tx.send(DeviceEvent::Lost(peer));
+        }
+    }
+
+    // spawns the task expiring peers the first time a peer is added within a runtime, the task
+    // stops once every clone of the registry is dropped
+    fn start_expiry(&self) {
+        let Ok(runtime) = tokio::runtime::Handle::try_current() else {
+            return;
+        };
+        if self.expiry_started.swap(true, Ordering::Relaxed) {
+            return;
+        }
+
+        let peers = Arc::downgrade(&self.peers);
+        let event_tx = self.event_tx.clone();
+        let ttl = self.ttl;
+        let expiry_started = self.expiry_started.clone();
+        runtime.spawn(async move {
+            let mut interval = tokio::time::interval(ttl / 2);
+            loop {
+                interval.tick().await;
+                let Some(peers) = peers.upgrade() else {
+                    break;
+                };
+                let registry = PeerRegistry {
+                    peers,
+                    event_tx: event_tx.clone(),
+                    ttl,
+                    expiry_started: expiry_started.clone(),
+                };
+                registry.expire();
+            }
+        });
+    }
+
+    /// Stream of events for peers discovered, updated or lost after the call.
+    pub fn events(&self) -> impl Stream<Item = DeviceEvent> {
+        futures::stream::unfold(self.event_tx.subscribe(), |mut event_rx| async move {
+            loop {
+                match event_rx.recv().await {
+                    Ok(event) => return Some((event, event_rx)),
+                    // slow consumers miss events instead of blocking the scanner
+                    Err(broadcast::error::RecvError::Lagged(_)) => continue,
+                    Err(broadcast::error::RecvError::Closed) => return None,
+                }
+            }
+        })
+    }
+}
+
+fn is_same_device(a: &DeviceInfo, b: &DeviceInfo) -> bool {
+    a.alias == b.alias
+        && a.ip == b.ip
+        && a.scope_id == b.scope_id
+        && a.port == b.port
+        && a.protocol == b.protocol
+        && a.device_type == b.device_type
+        && a.device_model == b.device_model
+        && a.version == b.version
+}
+
+#[cfg(test)]
+mod tests {
+    use futures::StreamExt;
+
+    use super::*;
+
+    #[test]
+    fn zero_ttl_is_rejected() {
+        assert!(PeerRegistry::new(Duration::ZERO).is_err());
+    }
+
+    #[tokio::test]
+    async fn peers_expire_without_discovery_running() {
+        let registry = PeerRegistry::new(Duration::from_millis(50)).unwrap();
+        let mut events = registry.events().boxed();
+        registry.upsert(DeviceInfo {
+            fingerprint: "peer".into(),
+            ..Default::default()
+        });
+        registry.upsert_static(DeviceInfo {
+            fingerprint: "static".into(),
+            ..Default::default()
+        });
+
+        let lost = tokio::time::timeout(Duration::from_secs(1), async {
+            loop {
+                if let Some(DeviceEvent::Lost(peer)) = events.next().await {
+                    return peer;
+                }
+            }
+        })
+        .await
+        .unwrap();
+        assert_eq!(lost.device_info.fingerprint, "peer");
+        assert!(registry.get("static").is_some());
+    }
+
+    #[test]
+    fn announcements_dont_move_a_known_fingerprint() {
+        let registry = PeerRegistry::default();
+        let peer = DeviceInfo {
+            fingerprint: "peer".into(),
+            ip: [192, 0, 2, 1].into(),
+            ..Default::default()
+        };
+        registry.upsert(peer.clone());
+        registry.upsert(DeviceInfo {
+            alias: "impostor".into(),
+            ip: [192, 0, 2, 2].into(),
+            ..peer.clone()
+        });
+
+        let known_peer = registry.get("peer").unwrap();
+        assert_eq!(known_peer.device_info.ip, peer.ip);
+        assert_eq!(known_peer.device_info.alias, peer.alias);
+    }
+}
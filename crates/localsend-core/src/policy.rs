@@ -0,0 +1,233 @@
+use std::{fmt, path::Path, str::FromStr};
+
+use serde::Deserialize;
+use time::{OffsetDateTime, UtcOffset};
+
+use crate::{FileType, PeerRegistry, Result, SendRequest};
+
+/// What happens to a send request matching a rule.
+#[derive(Clone, Copy, Debug, PartialEq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum PolicyAction {
+    Accept,
+    Decline,
+}
+
+/// Time of day written as `HH:MM`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Deserialize)]
+#[serde(try_from = "String")]
+pub struct TimeOfDay {
+    hour: u8,
+    minute: u8,
+}
+
+impl TimeOfDay {
+    pub fn new(hour: u8, minute: u8) -> Option<Self> {
+        (hour < 24 && minute < 60).then_some(Self { hour, minute })
+    }
+}
+
+impl FromStr for TimeOfDay {
+    type Err = String;
+
+    fn from_str(time: &str) -> std::result::Result<Self, Self::Err> {
+        let (hour, minute) = time
+            .split_once(':')
+            .ok_or_else(|| format!("invalid time of day {}, expected HH:MM", time))?;
+        hour.parse()
+            .ok()
+            .zip(minute.parse().ok())
+            .and_then(|(hour, minute)| Self::new(hour, minute))
+            .ok_or_else(|| format!("invalid time of day {}, expected HH:MM", time))
+    }
+}
+
+impl TryFrom<String> for TimeOfDay {
+    type Error = String;
+
+    fn try_from(time: String) -> std::result::Result<Self, Self::Error> {
+        time.parse()
+    }
+}
+
+impl fmt::Display for TimeOfDay {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:02}:{:02}", self.hour, self.minute)
+    }
+}
+
+/// Part of the day a rule applies in, from `start` up to but not including `end`. Windows with
+/// `end` before `start` go past midnight.
+#[derive(Clone, Copy, Debug, PartialEq, Deserialize)]
+pub struct TimeWindow {
+    pub start: TimeOfDay,
+    pub end: TimeOfDay,
+}
+
+impl TimeWindow {
+    pub fn contains(&self, time: TimeOfDay) -> bool {
+        if self.start <= self.end {
+            self.start <= time && time < self.end
+        } else {
+            self.start <= time || time < self.end
+        }
+    }
+}
+
+/// A rule matches a send request if all of its conditions do, conditions that are left out
+/// match any request.
+#[derive(Clone, Debug, Deserialize)]
+pub struct PolicyRule {
+    /// Name the rule is reported by when it matches.
+    pub name: String,
+    pub action: PolicyAction,
+    /// Fingerprints of the senders the rule applies to. Senders only claim their fingerprint,
+    /// so for accept rules it also has to be the one a known peer at the sender's address
+    /// announced, see [`ReceivePolicy::evaluate`].
+    ///
+    /// This doesn't authenticate the sender. Announcements are unauthenticated too, the peer
+    /// first heard with a fingerprint keeps it, but any device can claim a fingerprint that
+    /// isn't known yet or has expired, and anyone able to send from a trusted address is
+    /// trusted.
+    #[serde(default)]
+    pub fingerprints: Vec<String>,
+    /// Aliases of the senders the rule applies to. Aliases are chosen by the sender and anyone
+    /// can claim one, don't let accept rules rely on them alone.
+    #[serde(default)]
+    pub aliases: Vec<String>,
+    /// Upper bound on the size of all files of the request in bytes.
+    #[serde(default)]
+    pub max_total_size: Option<u64>,
+    #[serde(default)]
+    pub max_files: Option<usize>,
+    /// Every file has to have one of these extensions, compared without the dot and ignoring
+    /// case.
+    #[serde(default)]
+    pub extensions: Vec<String>,
+    /// Every file has to be of one of these types.
+    #[serde(default)]
+    pub file_types: Vec<FileType>,
+    /// Local time the request has to arrive in.
+    #[serde(default)]
+    pub time_window: Option<TimeWindow>,
+}
+
+impl PolicyRule {
+    fn matches(&self, send_request: &SendRequest, now: TimeOfDay, registry: &PeerRegistry) -> bool {
+        let sender = &send_request.device_info;
+        let files = || send_request.files.values();
+        // a spoofed fingerprint only gets a declined request, there is no point in checking it
+        let fingerprint_matches = || {
+            self.fingerprints.contains(&sender.fingerprint)
+                && (self.action == PolicyAction::Decline
+                    || registry
+                        .get(&sender.fingerprint)
+                        .is_some_and(|peer| peer.device_info.ip == sender.ip))
+        };
+
+        (self.fingerprints.is_empty() || fingerprint_matches())
+            && (self.aliases.is_empty() || self.aliases.contains(&sender.alias))
+            && self.max_total_size.is_none_or(|max_total_size| {
+                files().map(|file_info| file_info.size as u64).sum::<u64>() <= max_total_size
+            })
+            && self
+                .max_files
+                .is_none_or(|max_files| send_request.files.len() <= max_files)
+            && (self.extensions.is_empty()
+                || files().all(|file_info| {
+                    let extension = Path::new(&file_info.file_name)
+                        .extension()
+                        .and_then(|extension| extension.to_str());
+                    extension.is_some_and(|extension| {
+                        self.extensions
+                            .iter()
+                            .any(|allowed| allowed.eq_ignore_ascii_case(extension))
+                    })
+                }))
+            && (self.file_types.is_empty()
+                || files().all(|file_info| self.file_types.contains(&file_info.file_type)))
+            && self
+                .time_window
+                .is_none_or(|time_window| time_window.contains(now))
+    }
+}
+
+/// The rule that decided on a send request.
+#[derive(Clone, Debug)]
+pub struct PolicyMatch {
+    pub rule: String,
+    pub action: PolicyAction,
+}
+
+/// Rules that accept or decline send requests without asking the receive handler.
+///
+/// Decline rules are checked before accept rules, so a blocked sender can't be let in by an
+/// accept rule. Otherwise the first matching rule wins, and requests no rule matches go to the
+/// receive handler.
+#[derive(Clone, Debug, Deserialize)]
+pub struct ReceivePolicy {
+    #[serde(default)]
+    pub rules: Vec<PolicyRule>,
+    /// Offset of the local time the time windows of the rules are in.
+    #[serde(skip, default = "utc")]
+    pub utc_offset: UtcOffset,
+}
+
+impl Default for ReceivePolicy {
+    fn default() -> Self {
+        Self {
+            rules: vec![],
+            utc_offset: utc(),
+        }
+    }
+}
+
+impl ReceivePolicy {
+    /// Reads the rules from a JSON file.
+    pub fn load(path: &Path) -> Result<Self> {
+        let policy = std::fs::read(path)?;
+        Ok(serde_json::from_slice(&policy)?)
+    }
+
+    /// Sets the offset of the local time, looking it up is only sound before the program
+    /// starts other threads, see [`UtcOffset::current_local_offset`].
+    pub fn with_utc_offset(mut self, utc_offset: UtcOffset) -> Self {
+        self.utc_offset = utc_offset;
+        self
+    }
+
+    /// The rule deciding on `send_request`, if any. The sender's ip has to be the address the
+    /// request came from: fingerprints of accept rules only match senders `registry` knows by
+    /// that fingerprint at that address.
+    pub fn evaluate(
+        &self,
+        send_request: &SendRequest,
+        registry: &PeerRegistry,
+    ) -> Option<PolicyMatch> {
+        let now = OffsetDateTime::now_utc().to_offset(self.utc_offset);
+        let now = TimeOfDay {
+            hour: now.hour(),
+            minute: now.minute(),
+        };
+
+        let decline_rules = self
+            .rules
+            .iter()
+            .filter(|rule| rule.action == PolicyAction::Decline);
+        let accept_rules = self
+            .rules
+            .iter()
+            .filter(|rule| rule.action == PolicyAction::Accept);
+        decline_rules
+            .chain(accept_rules)
+            .find(|rule| rule.matches(send_request, now, registry))
+            .map(|rule| PolicyMatch {
+                rule: rule.name.clone(),
+                action: rule.action,
+            })
+    }
+}
+
+fn utc() -> UtcOffset {
+    UtcOffset::UTC
+}
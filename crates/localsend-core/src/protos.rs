@@ -1,57 +1,245 @@
-use std::{collections::HashMap, sync::Arc, time::Instant};
+use std::{
+    collections::{HashMap, HashSet},
+    net::{IpAddr, Ipv4Addr, SocketAddr, SocketAddrV4, SocketAddrV6},
+    path::{Path, PathBuf},
+    str::FromStr,
+    sync::Arc,
+    time::{Duration, Instant},
+};
 
 use serde::{Deserialize, Serialize};
 use tokio::sync::{
     mpsc::{UnboundedReceiver, UnboundedSender},
-    Mutex,
+    Mutex, OwnedSemaphorePermit,
 };
+use tokio_util::sync::CancellationToken;
+use uuid::Uuid;
+
+use crate::{PolicyMatch, DEVICE_MODEL, DEVICE_TYPE, PROTOCOL_VERSION};
 
 pub type ReceiveState = Arc<Mutex<AppState>>;
 pub type Sender<T> = UnboundedSender<T>;
 pub type Receiver<T> = UnboundedReceiver<T>;
+// paths files are being written to, shared by all sessions of a server
+pub(crate) type ClaimedPaths = Arc<std::sync::Mutex<HashSet<PathBuf>>>;
 
-#[derive(Clone, Debug, Serialize, Deserialize)]
-#[serde(rename_all = "lowercase")]
+// v1 peers send one of the variants below, v2 peers send the mime type of the file instead
+#[derive(Clone, PartialEq, Debug, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase", from = "String")]
 pub enum FileType {
     Image,
     Video,
     Pdf,
     Text,
+    Apk,
     Other,
 }
 
+impl From<String> for FileType {
+    fn from(file_type: String) -> Self {
+        let file_type = file_type.to_ascii_lowercase();
+        match file_type.split_once('/') {
+            Some(("image", _)) => Self::Image,
+            Some(("video", _)) => Self::Video,
+            Some(("text", _)) => Self::Text,
+            Some((_, "pdf")) => Self::Pdf,
+            Some((_, "vnd.android.package-archive")) => Self::Apk,
+            Some(_) => Self::Other,
+            None => match file_type.as_str() {
+                "image" => Self::Image,
+                "video" => Self::Video,
+                "pdf" => Self::Pdf,
+                "text" => Self::Text,
+                "apk" => Self::Apk,
+                _ => Self::Other,
+            },
+        }
+    }
+}
+
+impl FileType {
+    pub fn from_path(path: &Path) -> Self {
+        let extension = path
+            .extension()
+            .and_then(|extension| extension.to_str())
+            .map(|extension| extension.to_ascii_lowercase());
+        match extension.as_deref() {
+            Some("jpg" | "jpeg" | "png" | "gif" | "webp" | "bmp" | "heic") => Self::Image,
+            Some("mp4" | "mkv" | "mov" | "webm" | "avi") => Self::Video,
+            Some("pdf") => Self::Pdf,
+            Some("txt" | "md") => Self::Text,
+            Some("apk") => Self::Apk,
+            _ => Self::Other,
+        }
+    }
+}
+
 #[derive(Clone, PartialEq, Debug)]
 pub enum ReceiveStatus {
-    // TODO: add status for cancelled
     Waiting,            // waiting for sender to send the files
     Receiving,          // in an ongoing session, receiving files
     Finished,           // all files received (end of session)
     FinishedWithErrors, // finished but some files could not be received (end of session)
+    Cancelled,          // cancelled by the sender or by us (end of session)
 }
 
 #[derive(Clone, Debug)]
-pub enum ClientMessage {
-    Allow(Vec<String>),
-    Decline,
+pub enum ServerMessage {
+    // a send request was decided on by a rule of the receive policy instead of the handler
+    PolicyDecision((SendRequest, PolicyMatch)),
+    // session id, file id and the number of bytes just received, 0 when the upload starts
+    SendFileRequest((String, String, usize)),
+    // session id, file id and what became of the file
+    FileReceived((String, String, FileOutcome)),
+    // session id
+    CancelSession(String),
+}
+
+#[derive(Clone, Copy, PartialEq, Debug, Default)]
+pub enum CollisionPolicy {
+    #[default]
+    Rename,
+    Overwrite,
+    Skip,
+    Fail,
+}
+
+impl FromStr for CollisionPolicy {
+    type Err = String;
+
+    fn from_str(policy: &str) -> Result<Self, Self::Err> {
+        match policy {
+            "rename" => Ok(Self::Rename),
+            "overwrite" => Ok(Self::Overwrite),
+            "skip" => Ok(Self::Skip),
+            "fail" => Ok(Self::Fail),
+            _ => Err(format!("unknown collision policy {}", policy)),
+        }
+    }
 }
 
 #[derive(Clone, Debug)]
-pub enum ServerMessage {
-    SendRequest(SendRequest),
-    SendFileRequest((String, usize)),
-    CancelSession,
+pub enum FileOutcome {
+    Saved(PathBuf),
+    Renamed(PathBuf),
+    Overwritten(PathBuf),
+    Skipped,
+    Failed(String),
+}
+
+impl FileOutcome {
+    pub fn path(&self) -> Option<&Path> {
+        match self {
+            Self::Saved(path) | Self::Renamed(path) | Self::Overwritten(path) => Some(path),
+            Self::Skipped | Self::Failed(_) => None,
+        }
+    }
+
+    pub fn is_failed(&self) -> bool {
+        matches!(self, Self::Failed(_))
+    }
 }
 
+#[derive(Clone, Debug)]
+pub enum SendMessage {
+    Accepted(Vec<FileInfo>),
+    SendFileProgress((String, usize)),
+    FileFinished(String),
+    FileFailed(String),
+}
+
+#[derive(Clone, Copy, PartialEq, Debug, Default, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum DeviceType {
+    Mobile,
+    #[default]
+    Desktop,
+    Web,
+    Headless,
+    Server,
+    // device types added by newer versions of the protocol
+    #[serde(other)]
+    Unknown,
+}
+
+#[derive(Clone, Copy, PartialEq, Debug, Default, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Protocol {
+    Http,
+    #[default]
+    Https,
+}
+
+impl Protocol {
+    pub fn scheme(&self) -> &'static str {
+        match self {
+            Protocol::Http => "http",
+            Protocol::Https => "https",
+        }
+    }
+}
+
+// v1 peers only send alias, device_type and device_model, the rest were added in v2
 #[derive(Clone, Debug, Serialize, Deserialize)]
 #[serde(rename_all = "camelCase")]
 pub struct DeviceInfo {
     pub alias: String,
-    pub device_type: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub version: Option<String>,
+    #[serde(default)]
+    pub device_type: DeviceType,
     pub device_model: Option<String>,
-    #[serde(skip)]
-    pub ip: String,
-    #[serde(skip)]
+    #[serde(default)]
+    pub fingerprint: String,
+    #[serde(default)]
     pub port: u16,
+    #[serde(default)]
+    pub protocol: Protocol,
+    #[serde(default)]
+    pub download: bool,
+    #[serde(skip, default = "unspecified_ip")]
+    pub ip: IpAddr,
+    /// Interface the peer was seen on, needed to reach link-local IPv6 addresses.
+    #[serde(skip)]
+    pub scope_id: u32,
+}
+
+impl DeviceInfo {
+    pub(crate) fn this_device(alias: String, fingerprint: String, port: u16) -> Self {
+        Self {
+            alias,
+            version: Some(PROTOCOL_VERSION.to_string()),
+            device_type: DEVICE_TYPE,
+            device_model: Some(DEVICE_MODEL.to_string()),
+            fingerprint,
+            port,
+            protocol: Protocol::Https,
+            download: false,
+            ip: unspecified_ip(),
+            scope_id: 0,
+        }
+    }
+
+    /// Address of the server of the peer.
+    pub fn socket_addr(&self) -> SocketAddr {
+        match self.ip {
+            IpAddr::V4(ip) => SocketAddr::V4(SocketAddrV4::new(ip, self.port)),
+            IpAddr::V6(ip) => SocketAddr::V6(SocketAddrV6::new(ip, self.port, 0, self.scope_id)),
+        }
+    }
+
+    /// Records `addr` as the address the peer was seen at, keeping the port it announced.
+    pub(crate) fn set_addr(&mut self, addr: SocketAddr) {
+        self.ip = addr.ip().to_canonical();
+        self.scope_id = match addr {
+            SocketAddr::V6(addr) if self.ip.is_ipv6() => addr.scope_id(),
+            _ => 0,
+        };
+    }
+}
+
+fn unspecified_ip() -> IpAddr {
+    IpAddr::V4(Ipv4Addr::UNSPECIFIED)
 }
 
 impl PartialEq for DeviceInfo {
@@ -64,10 +252,15 @@ impl Default for DeviceInfo {
     fn default() -> Self {
         Self {
             alias: "".into(),
-            device_type: "".into(),
+            version: None,
+            device_type: DeviceType::default(),
             device_model: None,
-            ip: "".into(),
+            fingerprint: "".into(),
             port: 0,
+            protocol: Protocol::default(),
+            download: false,
+            ip: unspecified_ip(),
+            scope_id: 0,
         }
     }
 }
@@ -77,16 +270,30 @@ impl Default for DeviceInfo {
 pub struct DeviceResponse {
     #[serde(flatten)]
     pub device_info: DeviceInfo,
+    // v1 peers use `announcement`, v2 peers use `announce` but still send both
+    #[serde(default)]
     pub announcement: bool,
-    pub fingerprint: String,
+    #[serde(default)]
+    pub announce: bool,
+}
+
+impl DeviceResponse {
+    pub fn is_announcement(&self) -> bool {
+        self.announcement || self.announce
+    }
+
+    pub fn set_announcement(&mut self, announcement: bool) {
+        self.announcement = announcement;
+        self.announce = announcement;
+    }
 }
 
 impl From<DeviceInfo> for DeviceResponse {
     fn from(device: DeviceInfo) -> Self {
         Self {
             device_info: device,
-            fingerprint: "".into(),
             announcement: false,
+            announce: false,
         }
     }
 }
@@ -94,7 +301,7 @@ impl From<DeviceInfo> for DeviceResponse {
 impl PartialEq for DeviceResponse {
     // https://www.reddit.com/r/rust/comments/t8d6wb/comment/hznabrt
     fn eq(&self, other: &Self) -> bool {
-        self.fingerprint == other.fingerprint
+        self.device_info.fingerprint == other.device_info.fingerprint
     }
 }
 
@@ -105,8 +312,10 @@ pub struct FileInfo {
     pub size: usize, // bytes
     pub file_name: String,
     pub file_type: FileType,
-    // pub token: String,   // TODO: use this to verify while receiving the file
-    // preview_data: type? // nullable
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub sha256: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub preview: Option<String>,
 }
 
 #[derive(Clone, Debug, Serialize, Deserialize)]
@@ -116,38 +325,233 @@ pub struct SendRequest {
     pub files: HashMap<String, FileInfo>,
 }
 
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct PrepareUploadResponse {
+    pub session_id: String,
+    pub files: HashMap<String, String>,
+}
+
+// v1 peers don't send a session id
 #[derive(Debug, Serialize, Deserialize)]
 #[serde(rename_all = "camelCase")]
 pub struct SendInfo {
+    #[serde(default)]
+    pub session_id: Option<String>,
     pub file_id: String,
     pub token: String,
 }
 
-#[derive(Clone)]
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct CancelInfo {
+    pub session_id: String,
+}
+
+/// A send request that was accepted. Files of a session are received in parallel, so the state
+/// of each file has its own lock instead of going through the [`AppState`] of the server.
 pub struct ReceiveSession {
+    pub session_id: String,
     pub sender: DeviceInfo,
+    pub sender_addr: IpAddr,
     pub files: HashMap<String, FileInfo>,
-    pub file_status: HashMap<String, ReceiveStatus>,
-    pub destination_directory: String,
+    pub file_status: HashMap<String, std::sync::Mutex<ReceiveStatus>>,
+    pub tokens: HashMap<String, String>,
+    pub destination_directory: PathBuf,
     pub start_time: Instant,
-    pub status: ReceiveStatus,
+    pub status: std::sync::Mutex<ReceiveStatus>,
+    // paths files are being written to, so files with the same name don't end up writing to the
+    // same file, and the ones this session claimed there, released when it is dropped
+    claimed_paths: ClaimedPaths,
+    own_paths: std::sync::Mutex<Vec<PathBuf>>,
+    // stops the uploads of this session that are still in flight when it is cancelled
+    cancel_token: CancellationToken,
+    // when file data last arrived, sessions left idle for too long are cancelled
+    last_activity: std::sync::Mutex<Instant>,
 }
 
 impl ReceiveSession {
-    pub fn new(sender: DeviceInfo, destination_directory: String) -> Self {
+    pub fn new(sender: DeviceInfo, sender_addr: IpAddr, destination_directory: PathBuf) -> Self {
         Self {
+            session_id: Uuid::new_v4().to_string(),
             sender,
+            sender_addr,
             destination_directory,
             files: HashMap::new(),
             file_status: HashMap::new(),
+            tokens: HashMap::new(),
             start_time: Instant::now(),
-            status: ReceiveStatus::Waiting,
+            status: std::sync::Mutex::new(ReceiveStatus::Waiting),
+            claimed_paths: ClaimedPaths::default(),
+            own_paths: std::sync::Mutex::new(Vec::new()),
+            cancel_token: CancellationToken::new(),
+            last_activity: std::sync::Mutex::new(Instant::now()),
+        }
+    }
+
+    /// Claims paths in `claimed_paths` instead of a set of its own, so that files of other
+    /// sessions sharing it don't write to the same path either.
+    pub(crate) fn with_claimed_paths(mut self, claimed_paths: ClaimedPaths) -> Self {
+        self.claimed_paths = claimed_paths;
+        self
+    }
+
+    pub(crate) fn touch(&self) {
+        *self.last_activity.lock().unwrap() = Instant::now();
+    }
+
+    /// Time since an upload of this session started or received data.
+    pub fn idle_time(&self) -> Duration {
+        self.last_activity.lock().unwrap().elapsed()
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.cancel_token.is_cancelled()
+    }
+
+    // marks the session and its unfinished files as cancelled and stops their uploads
+    pub(crate) fn cancel(&self) {
+        *self.status.lock().unwrap() = ReceiveStatus::Cancelled;
+        for file_status in self.file_status.values() {
+            let mut file_status = file_status.lock().unwrap();
+            if matches!(
+                *file_status,
+                ReceiveStatus::Waiting | ReceiveStatus::Receiving
+            ) {
+                *file_status = ReceiveStatus::Cancelled;
+            }
+        }
+        self.cancel_token.cancel();
+    }
+
+    /// Completes once the session is cancelled.
+    pub async fn cancelled(&self) {
+        self.cancel_token.cancelled().await
+    }
+
+    /// Marks `file_id` as being received, false if it is unknown or was already uploaded.
+    pub(crate) fn start_file(&self, file_id: &str) -> bool {
+        let Some(file_status) = self.file_status.get(file_id) else {
+            return false;
+        };
+        let mut file_status = file_status.lock().unwrap();
+        if *file_status != ReceiveStatus::Waiting {
+            return false;
+        }
+        *file_status = ReceiveStatus::Receiving;
+        *self.status.lock().unwrap() = ReceiveStatus::Receiving;
+        self.touch();
+        true
+    }
+
+    /// Marks `file_id` as done, returns whether that was the last file of the session.
+    pub(crate) fn finish_file(&self, file_id: &str, failed: bool) -> bool {
+        if self.is_cancelled() {
+            return false;
+        }
+        if let Some(file_status) = self.file_status.get(file_id) {
+            *file_status.lock().unwrap() = if failed {
+                ReceiveStatus::FinishedWithErrors
+            } else {
+                ReceiveStatus::Finished
+            };
+        }
+
+        let all_finished = self.file_status.values().all(|file_status| {
+            matches!(
+                *file_status.lock().unwrap(),
+                ReceiveStatus::Finished | ReceiveStatus::FinishedWithErrors
+            )
+        });
+        if all_finished {
+            // TODO: add support for FinishedWithErrors
+            *self.status.lock().unwrap() = ReceiveStatus::Finished;
+        }
+        all_finished
+    }
+
+    /// Claims `path` for a file of this session, false if another file already did.
+    pub(crate) fn claim_path(&self, path: &Path) -> bool {
+        let claimed = self
+            .claimed_paths
+            .lock()
+            .unwrap()
+            .insert(path.to_path_buf());
+        if claimed {
+            self.own_paths.lock().unwrap().push(path.to_path_buf());
+        }
+        claimed
+    }
+
+    /// Whether a file of this or another live session writes to `path`.
+    pub(crate) fn is_path_claimed(&self, path: &Path) -> bool {
+        self.claimed_paths.lock().unwrap().contains(path)
+    }
+
+    /// Checks that an upload for `file_id` comes from the sender of this session and carries the
+    /// token that was handed out for it.
+    pub fn is_authorized(&self, sender_addr: IpAddr, file_id: &str, token: &str) -> bool {
+        self.sender_addr == sender_addr
+            && self
+                .tokens
+                .get(file_id)
+                .is_some_and(|file_token| file_token == token)
+    }
+}
+
+// the files of the session are on disk or failed once the last upload let go of it, from then on
+// a file with the same name is a collision like any other
+impl Drop for ReceiveSession {
+    fn drop(&mut self) {
+        let mut claimed_paths = self.claimed_paths.lock().unwrap();
+        for path in self.own_paths.get_mut().unwrap().drain(..) {
+            claimed_paths.remove(&path);
         }
     }
 }
 
+// an accepted session, holding on to its session slot of the server until it is removed
+pub(crate) struct ActiveSession {
+    pub(crate) receive_session: Arc<ReceiveSession>,
+    pub(crate) _slot: OwnedSemaphorePermit,
+}
+
 pub struct AppState {
     pub(crate) server_tx: Sender<ServerMessage>,
-    pub(crate) client_rx: Receiver<ClientMessage>,
-    pub(crate) receive_session: Option<ReceiveSession>,
+    // keyed by session id
+    pub(crate) sessions: HashMap<String, ActiveSession>,
+    pub(crate) claimed_paths: ClaimedPaths,
+}
+
+impl AppState {
+    // v1 peers don't send a session id, their session is the one that handed them the token for
+    // the file. file ids are chosen by the sender, so they don't tell sessions apart
+    pub(crate) fn find_session(
+        &self,
+        session_id: Option<&str>,
+        sender_addr: IpAddr,
+        file_id: &str,
+        token: &str,
+    ) -> Option<Arc<ReceiveSession>> {
+        let active_session = match session_id {
+            Some(session_id) => self.sessions.get(session_id),
+            None => self.sessions.values().find(|active_session| {
+                active_session
+                    .receive_session
+                    .is_authorized(sender_addr, file_id, token)
+            }),
+        };
+        active_session.map(|active_session| active_session.receive_session.clone())
+    }
+
+    // ends the session, uploads still in flight are stopped and their partial files removed
+    pub(crate) fn cancel_session(&mut self, session_id: &str) {
+        if let Some(active_session) = self.sessions.remove(session_id) {
+            active_session.receive_session.cancel();
+            // TODO(notjedi): clear buffer of sender_tx
+            let _ = self
+                .server_tx
+                .send(ServerMessage::CancelSession(session_id.to_string()));
+        }
+    }
 }
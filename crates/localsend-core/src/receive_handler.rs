@@ -0,0 +1,37 @@
+use std::{future::Future, path::PathBuf};
+
+use async_trait::async_trait;
+
+use crate::SendRequest;
+
+#[derive(Clone, Debug)]
+pub enum ReceiveDecision {
+    Accept {
+        file_ids: Vec<String>,
+        // overrides the destination directory of the server for this session
+        destination: Option<PathBuf>,
+    },
+    Decline,
+}
+
+/// Decides whether to accept an incoming send request, usually by asking the user.
+///
+/// The server keeps serving other requests while a decision is pending, and declines the
+/// request on its own if no decision is made within
+/// [`ServerConfig::decision_timeout`](crate::ServerConfig::decision_timeout). Closures taking
+/// the request and returning a future of the decision implement this too.
+#[async_trait]
+pub trait ReceiveHandler: Send + Sync {
+    async fn decide(&self, send_request: SendRequest) -> ReceiveDecision;
+}
+
+#[async_trait]
+impl<F, Fut> ReceiveHandler for F
+where
+    F: Fn(SendRequest) -> Fut + Send + Sync,
+    Fut: Future<Output = ReceiveDecision> + Send,
+{
+    async fn decide(&self, send_request: SendRequest) -> ReceiveDecision {
+        self(send_request).await
+    }
+}
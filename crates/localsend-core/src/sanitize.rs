@@ -0,0 +1,134 @@
+use std::path::PathBuf;
+
+use unicode_normalization::UnicodeNormalization;
+
+// names that windows refuses to create files with, regardless of the extension
+const RESERVED_NAMES: [&str; 22] = [
+    "CON", "PRN", "AUX", "NUL", "COM1", "COM2", "COM3", "COM4", "COM5", "COM6", "COM7", "COM8",
+    "COM9", "LPT1", "LPT2", "LPT3", "LPT4", "LPT5", "LPT6", "LPT7", "LPT8", "LPT9",
+];
+
+const INVALID_CHARS: [char; 7] = ['<', '>', ':', '"', '|', '?', '*'];
+
+/// Turns a file name sent by a peer into a relative path that is safe to join onto the
+/// destination directory.
+///
+/// Peers may send nested names like `dir/file.txt` when sending folders, so separators are kept,
+/// but `.` and `..` components are dropped, control and reserved characters are replaced and the
+/// name is normalized to NFC. Returns `None` for absolute paths, reserved device names and names
+/// that end up empty.
+pub fn sanitize_file_name(file_name: &str) -> Option<PathBuf> {
+    let file_name: String = file_name.nfc().collect();
+    if is_absolute(&file_name) {
+        return None;
+    }
+
+    let mut path = PathBuf::new();
+    for component in file_name.split(['/', '\\']) {
+        if component.is_empty() || component == "." || component == ".." {
+            continue;
+        }
+
+        let component: String = component
+            .chars()
+            .filter(|c| !c.is_control())
+            .map(|c| if INVALID_CHARS.contains(&c) { '_' } else { c })
+            .collect();
+        // windows silently strips trailing dots and spaces, which would let `..` sneak through
+        let component = component.trim_end_matches(['.', ' ']);
+        if component.is_empty() {
+            continue;
+        }
+        if is_reserved(component) {
+            return None;
+        }
+        path.push(component);
+    }
+
+    if path.as_os_str().is_empty() {
+        None
+    } else {
+        Some(path)
+    }
+}
+
+fn is_absolute(file_name: &str) -> bool {
+    let bytes = file_name.as_bytes();
+    let has_drive_letter = bytes.len() >= 2 && bytes[0].is_ascii_alphabetic() && bytes[1] == b':';
+    file_name.starts_with(['/', '\\']) || has_drive_letter
+}
+
+fn is_reserved(component: &str) -> bool {
+    let stem = component.split('.').next().unwrap_or(component).trim_end();
+    RESERVED_NAMES
+        .iter()
+        .any(|reserved| reserved.eq_ignore_ascii_case(stem))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::Path;
+
+    use super::*;
+
+    #[test]
+    fn parent_components_are_dropped() {
+        assert_eq!(
+            sanitize_file_name("../../.bashrc").unwrap(),
+            Path::new(".bashrc")
+        );
+        assert_eq!(
+            sanitize_file_name("a/./../b").unwrap(),
+            Path::new("a").join("b")
+        );
+        assert_eq!(sanitize_file_name("..\\..\\x").unwrap(), Path::new("x"));
+    }
+
+    #[test]
+    fn absolute_paths_are_rejected() {
+        assert_eq!(sanitize_file_name("/etc/passwd"), None);
+        assert_eq!(sanitize_file_name("C:\\x"), None);
+        assert_eq!(sanitize_file_name("c:x"), None);
+        assert_eq!(sanitize_file_name("\\\\server\\share"), None);
+    }
+
+    #[test]
+    fn reserved_names_are_rejected() {
+        assert_eq!(sanitize_file_name("CON.txt"), None);
+        assert_eq!(sanitize_file_name("dir/lpt1"), None);
+        assert_eq!(sanitize_file_name("nul .tar.gz"), None);
+        assert_eq!(
+            sanitize_file_name("CONSOLE.txt").unwrap(),
+            Path::new("CONSOLE.txt")
+        );
+    }
+
+    #[test]
+    fn names_that_end_up_empty_are_rejected() {
+        assert_eq!(sanitize_file_name(""), None);
+        assert_eq!(sanitize_file_name(".. "), None);
+        assert_eq!(sanitize_file_name("./.../. ./"), None);
+        assert_eq!(sanitize_file_name("\u{0}\u{1b}"), None);
+    }
+
+    #[test]
+    fn control_and_invalid_characters_are_replaced() {
+        assert_eq!(
+            sanitize_file_name("a\u{0}b\nc\u{7f}.txt").unwrap(),
+            Path::new("abc.txt")
+        );
+        assert_eq!(
+            sanitize_file_name("what?<*>.txt").unwrap(),
+            Path::new("what____.txt")
+        );
+        assert_eq!(sanitize_file_name("name. . ").unwrap(), Path::new("name"));
+    }
+
+    #[test]
+    fn names_are_normalized_to_nfc() {
+        let nfd = sanitize_file_name("cafe\u{301}.txt").unwrap();
+        let nfc = sanitize_file_name("caf\u{e9}.txt").unwrap();
+        assert_eq!(nfd, nfc);
+        assert_eq!(nfc, Path::new("caf\u{e9}.txt"));
+    }
+}
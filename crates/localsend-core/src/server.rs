@@ -1,67 +1,229 @@
 use std::{
     collections::HashMap,
+    ffi::OsString,
     io,
-    net::{Ipv4Addr, SocketAddr},
+    net::{IpAddr, SocketAddr},
     path::{Path, PathBuf},
-    sync::Arc,
+    sync::{Arc, Weak},
+    time::Duration,
 };
 
 use axum::{
     body::Bytes,
-    extract::{BodyStream, Query, State},
+    extract::{BodyStream, ConnectInfo, Query, State},
     http::StatusCode,
-    routing::post,
-    BoxError, Json, Router,
+    response::{IntoResponse, Response},
+    routing::{get, post},
+    BoxError, Extension, Json, Router,
 };
 use axum_server::tls_rustls::RustlsConfig;
-use futures::{Stream, TryStreamExt};
+use futures::{Stream, StreamExt, TryStreamExt};
+use socket2::{Domain, Socket, Type};
 use tokio::{
-    fs::File,
+    fs::{File, OpenOptions},
     io::{AsyncReadExt, AsyncWriteExt, BufWriter},
-    sync::Mutex,
+    sync::{Mutex, OwnedSemaphorePermit, Semaphore},
 };
-use tokio_util::io::StreamReader;
-use tracing::{info, trace};
+use tokio_util::{io::StreamReader, task::AbortOnDropHandle};
+use tracing::{info, trace, warn};
 use uuid::Uuid;
 
 use crate::{
-    utils, AppState, ClientMessage, ReceiveSession, ReceiveState, ReceiveStatus, Receiver,
-    SendInfo, SendRequest, Sender, ServerMessage,
+    device_scanner::validate_device_info, sanitize_file_name, tls, utils, ActiveSession, AppState,
+    CancelInfo, ClaimedPaths, CollisionPolicy, DeviceInfo, Error, FileOutcome, Identity,
+    NetworkEvent, NetworkWatcher, PeerRegistry, PolicyAction, PolicyMatch, PrepareUploadResponse,
+    Protocol, ReceiveDecision, ReceiveHandler, ReceivePolicy, ReceiveSession, ReceiveState,
+    ReceiveStatus, SendInfo, SendRequest, Sender, ServerMessage,
 };
 
+/// How long the receive handler has to decide on a send request by default.
+pub const DEFAULT_DECISION_TIMEOUT: Duration = Duration::from_secs(60);
+/// How long a queued send request waits for a session to finish by default.
+pub const DEFAULT_QUEUE_TIMEOUT: Duration = Duration::from_secs(120);
+/// How long an accepted session may go without receiving file data by default.
+pub const DEFAULT_SESSION_TIMEOUT: Duration = Duration::from_secs(300);
+
+#[derive(Clone, Debug)]
+pub struct ServerConfig {
+    /// Directory received files are saved to, unless the receive decision picks another one.
+    pub destination_directory: PathBuf,
+    /// What to do when a received file has the same name as an existing file.
+    pub collision_policy: CollisionPolicy,
+    /// Keep the partial file of failed or cancelled transfers instead of removing it. Partial
+    /// files are hidden, named `.{file name}.{random id}.part`.
+    pub keep_partial_files: bool,
+    /// Serve plain http instead of https, for peers with encryption turned off.
+    pub protocol: Protocol,
+    /// Send requests the receive handler hasn't decided on within this time are declined.
+    pub decision_timeout: Duration,
+    /// Rules deciding on send requests before the receive handler is asked.
+    pub policy: ReceivePolicy,
+    /// Sessions received at the same time, send requests waiting on a decision count as a
+    /// session. At least one.
+    pub max_concurrent_sessions: usize,
+    /// Send requests arriving while all sessions are taken wait in a queue of this size and are
+    /// decided on in order, once a session finishes. Without a queue they are rejected with
+    /// `409 Conflict` right away.
+    pub queue_size: usize,
+    /// Queued send requests are rejected after waiting this long.
+    pub queue_timeout: Duration,
+    /// Accepted sessions are cancelled once they received no file data for this long, so a
+    /// sender that went away doesn't keep its slot.
+    pub session_timeout: Duration,
+}
+
+impl Default for ServerConfig {
+    fn default() -> Self {
+        Self {
+            destination_directory: utils::default_destination_directory(),
+            collision_policy: CollisionPolicy::default(),
+            keep_partial_files: false,
+            protocol: Protocol::default(),
+            decision_timeout: DEFAULT_DECISION_TIMEOUT,
+            policy: ReceivePolicy::default(),
+            max_concurrent_sessions: 1,
+            queue_size: 0,
+            queue_timeout: DEFAULT_QUEUE_TIMEOUT,
+            session_timeout: DEFAULT_SESSION_TIMEOUT,
+        }
+    }
+}
+
+// permits for sessions and for places in the queue of send requests waiting on a session, both
+// are returned when dropped, e.g. when the sender hangs up while its request is queued
+struct SessionSlots {
+    sessions: Arc<Semaphore>,
+    queue: Arc<Semaphore>,
+}
+
+impl SessionSlots {
+    fn new(config: &ServerConfig) -> Self {
+        Self {
+            sessions: Arc::new(Semaphore::new(config.max_concurrent_sessions.max(1))),
+            queue: Arc::new(Semaphore::new(config.queue_size)),
+        }
+    }
+
+    // the semaphores are fair, a permit freed up goes to the longest waiting request instead
+    // of one that just arrived
+    async fn acquire(
+        &self,
+        queue_timeout: Duration,
+    ) -> Result<OwnedSemaphorePermit, (StatusCode, String)> {
+        if let Ok(slot) = self.sessions.clone().try_acquire_owned() {
+            return Ok(slot);
+        }
+
+        let Ok(_queue_slot) = self.queue.clone().try_acquire_owned() else {
+            // reject incoming request if other sessions are ongoing and the queue is full
+            return Err((StatusCode::CONFLICT, "Blocked by another sesssion".into()));
+        };
+        match tokio::time::timeout(queue_timeout, self.sessions.clone().acquire_owned()).await {
+            Ok(Ok(slot)) => Ok(slot),
+            Ok(Err(err)) => Err((StatusCode::INTERNAL_SERVER_ERROR, err.to_string())),
+            Err(_) => Err((
+                StatusCode::CONFLICT,
+                "Timed out waiting for another session".into(),
+            )),
+        }
+    }
+}
+
+/// Cancels the sessions of a [`Server`] from the receiving side, e.g. when the user aborts a
+/// transfer. Uploads still in flight are stopped and their partial files removed, unless
+/// [`ServerConfig::keep_partial_files`] is set.
+#[derive(Clone, Default)]
+pub struct CancelHandle {
+    // set once the server is started
+    session_state: Arc<std::sync::Mutex<Option<ReceiveState>>>,
+}
+
+impl CancelHandle {
+    /// Cancels every ongoing session, returns how many there were.
+    pub async fn cancel(&self) -> usize {
+        let Some(session_state) = self.session_state.lock().unwrap().clone() else {
+            return 0;
+        };
+        let mut session = session_state.lock().await;
+        let session_ids: Vec<String> = session.sessions.keys().cloned().collect();
+        for session_id in &session_ids {
+            info!("cancelling session {}", session_id);
+            session.cancel_session(session_id);
+        }
+        session_ids.len()
+    }
+}
+
 pub struct Server {
-    certificate: rcgen::Certificate,
-    interface_addr: Ipv4Addr,
+    identity: Identity,
+    this_device: DeviceInfo,
+    interface_addr: IpAddr,
     multicast_port: u16,
+    config: ServerConfig,
+    registry: PeerRegistry,
+    network_watcher: Option<NetworkWatcher>,
+    cancel_handle: CancelHandle,
 }
 
 impl Server {
-    pub fn new(interface_addr: Ipv4Addr, multicast_port: u16) -> Self {
+    pub fn new(
+        mut this_device: DeviceInfo,
+        identity: Identity,
+        interface_addr: IpAddr,
+        multicast_port: u16,
+        config: ServerConfig,
+    ) -> Self {
+        // info requests have to report the protocol we are actually serving
+        this_device.protocol = config.protocol;
         Self {
-            certificate: utils::generate_tls_cert(),
+            identity,
+            this_device,
             interface_addr,
             multicast_port,
+            config,
+            registry: PeerRegistry::default(),
+            network_watcher: None,
+            cancel_handle: CancelHandle::default(),
         }
     }
 
+    /// Adds peers registering with the server to `registry`, usually the one of the
+    /// [`DeviceScanner`](crate::DeviceScanner) whose announcements they reply to.
+    pub fn with_registry(mut self, registry: PeerRegistry) -> Self {
+        self.registry = registry;
+        self
+    }
+
+    /// Moves the server to the new address of its interface when the one it is bound to goes
+    /// away, e.g. after reconnecting to Wi-Fi. Servers bound to the unspecified address already
+    /// listen on every address and ignore the watcher.
+    pub fn with_network_watcher(mut self, watcher: NetworkWatcher) -> Self {
+        self.network_watcher = Some(watcher);
+        self
+    }
+
+    /// Handle to cancel the sessions of the server, which can be taken before it is started.
+    pub fn cancel_handle(&self) -> CancelHandle {
+        self.cancel_handle.clone()
+    }
+
+    /// Serves until the listener fails. Send requests are decided on by `receive_handler`,
+    /// progress of accepted sessions is reported over `server_tx`.
     pub async fn start_server(
         &self,
         server_tx: Sender<ServerMessage>,
-        client_rx: Receiver<ClientMessage>,
-    ) {
-        let cert_pem = self.certificate.serialize_pem().unwrap();
-        let private_key_pem = self.certificate.serialize_private_key_pem();
-        let config = RustlsConfig::from_pem(cert_pem.into_bytes(), private_key_pem.into_bytes())
-            .await
-            .unwrap();
-
+        receive_handler: impl ReceiveHandler + 'static,
+    ) -> Result<(), Error> {
         let app_state = Arc::new(Mutex::new(AppState {
             server_tx,
-            client_rx,
-            receive_session: None,
+            sessions: HashMap::new(),
+            claimed_paths: ClaimedPaths::default(),
         }));
+        *self.cancel_handle.session_state.lock().unwrap() = Some(app_state.clone());
+        let receive_handler: Arc<dyn ReceiveHandler> = Arc::new(receive_handler);
 
         let app = Router::new()
+            .route("/api/localsend/v1/info", get(Self::handle_info_request))
             .route(
                 "/api/localsend/v1/send-request",
                 post(Self::handle_send_request),
@@ -74,169 +236,750 @@ impl Server {
                 "/api/localsend/v1/cancel",
                 post(Self::handle_cancel_request),
             )
+            .route("/api/localsend/v2/info", get(Self::handle_info_request))
+            .route(
+                "/api/localsend/v2/register",
+                post(Self::handle_register_request),
+            )
+            .route(
+                "/api/localsend/v2/prepare-upload",
+                post(Self::handle_prepare_upload_request),
+            )
+            .route(
+                "/api/localsend/v2/upload",
+                post(Self::handle_send_file_request),
+            )
+            .route(
+                "/api/localsend/v2/cancel",
+                post(Self::handle_cancel_session_request),
+            )
+            // kept out of AppState so that info requests don't wait on an ongoing send request
+            .layer(Extension(Arc::new(self.this_device.clone())))
+            .layer(Extension(Arc::new(self.config.clone())))
+            .layer(Extension(self.registry.clone()))
+            .layer(Extension(receive_handler))
+            .layer(Extension(Arc::new(SessionSlots::new(&self.config))))
             .with_state(app_state);
 
-        let addr = SocketAddr::from((self.interface_addr, self.multicast_port));
-        info!("listening on {}", addr);
-        axum_server::bind_rustls(addr, config)
-            .serve(app.into_make_service())
-            .await
-            .unwrap();
+        let tls_config = match self.config.protocol {
+            Protocol::Http => None,
+            Protocol::Https => Some(RustlsConfig::from_config(Arc::new(tls::server_config(
+                &self.identity,
+            )?))),
+        };
+        let mut network_events = match self.network_watcher.as_ref() {
+            Some(network_watcher) if !self.interface_addr.is_unspecified() => {
+                network_watcher.events().boxed()
+            }
+            _ => futures::stream::pending().boxed(),
+        };
+
+        let mut addr = SocketAddr::from((self.interface_addr, self.multicast_port));
+        loop {
+            let listener = bind_listener(addr).map_err(|source| Error::Bind { addr, source })?;
+            info!("listening on {}://{}", self.config.protocol.scheme(), addr);
+            let serve = serve(listener, app.clone(), tls_config.clone());
+            tokio::pin!(serve);
+
+            let mut lost_interface = None;
+            addr = loop {
+                tokio::select! {
+                    result = &mut serve => {
+                        return result.map_err(|source| Error::Bind { addr, source });
+                    }
+                    Some(event) = network_events.next() => match event {
+                        NetworkEvent::AddressRemoved(interface) if interface.addr == addr.ip() => {
+                            info!("{} went away, waiting for {} to get a new address", addr, interface.name);
+                            lost_interface = Some(interface.name);
+                        }
+                        NetworkEvent::AddressAdded(interface)
+                            if lost_interface.as_ref() == Some(&interface.name)
+                                && interface.addr.is_ipv4() == addr.is_ipv4() =>
+                        {
+                            break SocketAddr::new(interface.addr, addr.port());
+                        }
+                        _ => {}
+                    },
+                }
+            };
+            // dropping the server only closes the listener, accepted connections keep going
+        }
+    }
+
+    async fn handle_info_request(
+        Extension(this_device): Extension<Arc<DeviceInfo>>,
+    ) -> Json<DeviceInfo> {
+        Json(this_device.as_ref().clone())
+    }
+
+    async fn handle_register_request(
+        Extension(this_device): Extension<Arc<DeviceInfo>>,
+        Extension(registry): Extension<PeerRegistry>,
+        ConnectInfo(addr): ConnectInfo<SocketAddr>,
+        Json(mut device_info): Json<DeviceInfo>,
+    ) -> Result<Json<DeviceInfo>, (StatusCode, String)> {
+        trace!("got register request {:#?}", device_info);
+        validate_device_info(&device_info)
+            .map_err(|err| (StatusCode::BAD_REQUEST, err.to_string()))?;
+
+        if device_info.fingerprint != this_device.fingerprint {
+            device_info.set_addr(addr);
+            registry.upsert(device_info);
+        }
+        Ok(Json(this_device.as_ref().clone()))
     }
 
     async fn handle_cancel_request(
         State(session_state): State<ReceiveState>,
+        ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    ) -> Result<(), (StatusCode, String)> {
+        Self::cancel_session(session_state, addr.ip().to_canonical(), None).await
+    }
+
+    async fn handle_cancel_session_request(
+        State(session_state): State<ReceiveState>,
+        ConnectInfo(addr): ConnectInfo<SocketAddr>,
+        params: Query<CancelInfo>,
+    ) -> Result<(), (StatusCode, String)> {
+        Self::cancel_session(
+            session_state,
+            addr.ip().to_canonical(),
+            Some(&params.session_id),
+        )
+        .await
+    }
+
+    async fn cancel_session(
+        session_state: ReceiveState,
+        sender_addr: IpAddr,
+        session_id: Option<&str>,
     ) -> Result<(), (StatusCode, String)> {
         let mut session = session_state.lock().await;
-        if session.receive_session.is_none() {
-            // reject incoming request if another session is ongoing
+        if session.sessions.is_empty() {
             return Err((
                 StatusCode::BAD_REQUEST,
                 "Cannot cancel a non existant session".into(),
             ));
         }
 
-        // TODO(notjedi): check if cancel request is valid by comparing the ip address
-        // TODO(notjedi): set session_state.receive_session to None
-        // TODO(notjedi): clear buffer of sender_tx
-        let _ = session.server_tx.send(ServerMessage::CancelSession);
+        // v1 peers don't send a session id, they cancel their own session. that is only known if
+        // it is the one session from their address
+        let session_id = match session_id {
+            Some(session_id) => session_id.to_string(),
+            None => {
+                let mut sender_sessions = session
+                    .sessions
+                    .iter()
+                    .filter(|(_, active_session)| {
+                        active_session.receive_session.sender_addr == sender_addr
+                    })
+                    .map(|(session_id, _)| session_id.clone());
+                match (sender_sessions.next(), sender_sessions.next()) {
+                    (Some(session_id), None) => session_id,
+                    (None, _) => return Err((StatusCode::FORBIDDEN, "Invalid IP address".into())),
+                    (Some(_), Some(_)) => {
+                        return Err((
+                            StatusCode::CONFLICT,
+                            "Several sessions from this address, cancel needs a session id".into(),
+                        ))
+                    }
+                }
+            }
+        };
+        let Some(active_session) = session.sessions.get(&session_id) else {
+            return Err((StatusCode::FORBIDDEN, "Invalid session id".into()));
+        };
 
-        session.receive_session = None;
+        // only the device that started the session is allowed to cancel it
+        if active_session.receive_session.sender_addr != sender_addr {
+            return Err((StatusCode::FORBIDDEN, "Invalid IP address".into()));
+        }
+
+        session.cancel_session(&session_id);
         Ok(())
     }
 
     async fn handle_send_request(
         State(session_state): State<ReceiveState>,
+        Extension(config): Extension<Arc<ServerConfig>>,
+        Extension(receive_handler): Extension<Arc<dyn ReceiveHandler>>,
+        Extension(slots): Extension<Arc<SessionSlots>>,
+        Extension(registry): Extension<PeerRegistry>,
+        ConnectInfo(addr): ConnectInfo<SocketAddr>,
         Json(send_request): Json<SendRequest>,
     ) -> Result<Json<HashMap<String, String>>, (StatusCode, String)> {
+        let (_, wanted_files) = Self::start_session(
+            session_state,
+            config,
+            receive_handler,
+            slots,
+            registry,
+            addr,
+            send_request,
+        )
+        .await?;
+        Ok(Json(wanted_files))
+    }
+
+    async fn handle_prepare_upload_request(
+        State(session_state): State<ReceiveState>,
+        Extension(config): Extension<Arc<ServerConfig>>,
+        Extension(receive_handler): Extension<Arc<dyn ReceiveHandler>>,
+        Extension(slots): Extension<Arc<SessionSlots>>,
+        Extension(registry): Extension<PeerRegistry>,
+        ConnectInfo(addr): ConnectInfo<SocketAddr>,
+        Json(send_request): Json<SendRequest>,
+    ) -> Result<Response, (StatusCode, String)> {
+        let (session_id, wanted_files) = Self::start_session(
+            session_state,
+            config,
+            receive_handler,
+            slots,
+            registry,
+            addr,
+            send_request,
+        )
+        .await?;
+        if wanted_files.is_empty() {
+            // nothing to transfer, the sender is done with this session
+            return Ok(StatusCode::NO_CONTENT.into_response());
+        }
+
+        Ok(Json(PrepareUploadResponse {
+            session_id,
+            files: wanted_files,
+        })
+        .into_response())
+    }
+
+    async fn start_session(
+        session_state: ReceiveState,
+        config: Arc<ServerConfig>,
+        receive_handler: Arc<dyn ReceiveHandler>,
+        slots: Arc<SessionSlots>,
+        registry: PeerRegistry,
+        addr: SocketAddr,
+        mut send_request: SendRequest,
+    ) -> Result<(String, HashMap<String, String>), (StatusCode, String)> {
         trace!("got request {:#?}", send_request);
+        send_request.device_info.set_addr(addr);
 
-        let mut session = session_state.lock().await;
-        if session.receive_session.is_some() {
-            // reject incoming request if another session is ongoing
-            return Err((StatusCode::CONFLICT, "Blocked by another sesssion".into()));
+        // file names end up being joined onto the destination directory, never trust them
+        for file_info in send_request.files.values_mut() {
+            match sanitize_file_name(&file_info.file_name) {
+                Some(file_name) => file_info.file_name = file_name.to_string_lossy().into_owned(),
+                None => {
+                    return Err((
+                        StatusCode::BAD_REQUEST,
+                        format!("Invalid file name {}", file_info.file_name),
+                    ))
+                }
+            }
         }
 
-        let _ = session
-            .server_tx
-            .send(ServerMessage::SendRequest(send_request.clone()));
-        let response = session.client_rx.recv().await;
+        // blocked senders are turned away right away instead of waiting for a slot
+        let policy_match = match config.policy.evaluate(&send_request, &registry) {
+            Some(policy_match) if policy_match.action == PolicyAction::Decline => {
+                Self::apply_policy(&session_state, &send_request, policy_match).await;
+                return Err((StatusCode::FORBIDDEN, "User declined the request".into()));
+            }
+            policy_match => policy_match,
+        };
+
+        // the slot is taken until the request is declined or its session ends
+        let slot = slots.acquire(config.queue_timeout).await?;
+
+        // decided on as part of the request, if the sender hangs up while we wait the decision is
+        // dropped with it and no session is left behind holding the slot
+        Self::decide(
+            session_state,
+            config,
+            receive_handler,
+            slot,
+            addr,
+            send_request,
+            policy_match,
+        )
+        .await
+    }
 
-        match response {
-            Some(ClientMessage::Decline) | None => {
-                Err((StatusCode::FORBIDDEN, "User declined the request".into()))
+    async fn decide(
+        session_state: ReceiveState,
+        config: Arc<ServerConfig>,
+        receive_handler: Arc<dyn ReceiveHandler>,
+        slot: OwnedSemaphorePermit,
+        addr: SocketAddr,
+        send_request: SendRequest,
+        policy_match: Option<PolicyMatch>,
+    ) -> Result<(String, HashMap<String, String>), (StatusCode, String)> {
+        let decision = match policy_match {
+            Some(policy_match) => {
+                Self::apply_policy(&session_state, &send_request, policy_match).await
             }
-            Some(ClientMessage::Allow(file_ids)) => {
-                // TODO: create destination_directory if it doesn't exist
-                let state = session.receive_session.insert(ReceiveSession::new(
-                    send_request.device_info,
-                    "./test_files/".into(),
-                ));
+            None => Self::ask(receive_handler, &config, send_request.clone()).await,
+        };
 
-                // TODO(notjedi): yo, why so many clones?
-                let mut wanted_files: HashMap<String, String> = HashMap::new();
-                file_ids.into_iter().for_each(|file_id| {
-                    let token = Uuid::new_v4();
-                    wanted_files.insert(file_id.clone(), token.to_string());
-                    state
-                        .files
-                        .insert(file_id.clone(), send_request.files[&file_id].clone());
-                    state.file_status.insert(file_id, ReceiveStatus::Waiting);
-                });
-                trace!("{:#?}", &wanted_files);
-                trace!("{:#?}, ", &state.files);
+        let (receive_session, wanted_files) =
+            Self::accept(&config, addr, send_request, decision).await?;
+        let session_id = receive_session.session_id.clone();
+        if wanted_files.is_empty() {
+            // nothing will be uploaded, so the session would never finish and keep its slot
+            return Ok((session_id, wanted_files));
+        }
+        let mut state = session_state.lock().await;
+        let receive_session =
+            Arc::new(receive_session.with_claimed_paths(state.claimed_paths.clone()));
+        tokio::spawn(Self::expire_idle_session(
+            session_state.clone(),
+            Arc::downgrade(&receive_session),
+            config.session_timeout,
+        ));
+        state.sessions.insert(
+            session_id.clone(),
+            ActiveSession {
+                receive_session,
+                _slot: slot,
+            },
+        );
+        Ok((session_id, wanted_files))
+    }
+
+    // cancels the session once it went `timeout` without file data, stops when the session ended
+    async fn expire_idle_session(
+        session_state: ReceiveState,
+        receive_session: Weak<ReceiveSession>,
+        timeout: Duration,
+    ) {
+        let session_id = loop {
+            let Some(receive_session) = receive_session.upgrade() else {
+                return;
+            };
+            let idle_time = receive_session.idle_time();
+            if idle_time >= timeout {
+                break receive_session.session_id.clone();
+            }
+            drop(receive_session);
+            tokio::time::sleep(timeout - idle_time).await;
+        };
+        info!(
+            "session {} was idle for {:?}, cancelling it",
+            session_id, timeout
+        );
+        session_state.lock().await.cancel_session(&session_id);
+    }
+
+    async fn apply_policy(
+        session_state: &ReceiveState,
+        send_request: &SendRequest,
+        policy_match: PolicyMatch,
+    ) -> ReceiveDecision {
+        let sender = &send_request.device_info;
+        info!(
+            "{:?} request of {} ({}) by rule {}",
+            policy_match.action, sender.alias, sender.fingerprint, policy_match.rule
+        );
+        let decision = match policy_match.action {
+            PolicyAction::Accept => ReceiveDecision::Accept {
+                file_ids: send_request.files.keys().cloned().collect(),
+                destination: None,
+            },
+            PolicyAction::Decline => ReceiveDecision::Decline,
+        };
+        let _ = session_state
+            .lock()
+            .await
+            .server_tx
+            .send(ServerMessage::PolicyDecision((
+                send_request.clone(),
+                policy_match,
+            )));
+        decision
+    }
 
-                Ok(Json(wanted_files))
+    async fn ask(
+        receive_handler: Arc<dyn ReceiveHandler>,
+        config: &ServerConfig,
+        send_request: SendRequest,
+    ) -> ReceiveDecision {
+        let alias = send_request.device_info.alias.clone();
+        // a panicking handler declines instead of leaving the server blocked, and the handler is
+        // stopped if the sender hangs up before it decided
+        let mut decision_task = AbortOnDropHandle::new(tokio::spawn(async move {
+            receive_handler.decide(send_request).await
+        }));
+        match tokio::time::timeout(config.decision_timeout, &mut decision_task).await {
+            Ok(Ok(decision)) => decision,
+            Ok(Err(err)) => {
+                warn!("receive handler failed, declining: {}", err);
+                ReceiveDecision::Decline
+            }
+            Err(_) => {
+                decision_task.abort();
+                info!(
+                    "no decision on the request of {} within {:?}, declining",
+                    alias, config.decision_timeout
+                );
+                ReceiveDecision::Decline
             }
         }
     }
 
+    async fn accept(
+        config: &ServerConfig,
+        addr: SocketAddr,
+        send_request: SendRequest,
+        decision: ReceiveDecision,
+    ) -> Result<(ReceiveSession, HashMap<String, String>), (StatusCode, String)> {
+        let (file_ids, destination) = match decision {
+            ReceiveDecision::Decline => {
+                return Err((StatusCode::FORBIDDEN, "User declined the request".into()))
+            }
+            ReceiveDecision::Accept {
+                file_ids,
+                destination,
+            } => (file_ids, destination),
+        };
+
+        let destination_directory =
+            destination.unwrap_or_else(|| config.destination_directory.clone());
+        tokio::fs::create_dir_all(&destination_directory)
+            .await
+            .map_err(|err| {
+                (
+                    StatusCode::INTERNAL_SERVER_ERROR,
+                    format!("Failed to create destination directory: {}", err),
+                )
+            })?;
+
+        let mut state = ReceiveSession::new(
+            send_request.device_info,
+            addr.ip().to_canonical(),
+            destination_directory,
+        );
+
+        let mut wanted_files: HashMap<String, String> = HashMap::new();
+        for file_id in file_ids {
+            // the handler may answer with ids that were never offered
+            let Some(file_info) = send_request.files.get(&file_id) else {
+                continue;
+            };
+            let token = Uuid::new_v4().to_string();
+            wanted_files.insert(file_id.clone(), token.clone());
+            state.files.insert(file_id.clone(), file_info.clone());
+            state.tokens.insert(file_id.clone(), token);
+            state
+                .file_status
+                .insert(file_id, std::sync::Mutex::new(ReceiveStatus::Waiting));
+        }
+        trace!("{:#?}", &wanted_files);
+        trace!("{:#?}, ", &state.files);
+
+        Ok((state, wanted_files))
+    }
+
     async fn handle_send_file_request(
         State(session_state): State<ReceiveState>,
+        Extension(config): Extension<Arc<ServerConfig>>,
+        ConnectInfo(addr): ConnectInfo<SocketAddr>,
         params: Query<SendInfo>,
         file_stream: BodyStream,
     ) -> Result<(), (StatusCode, String)> {
-        // NOTE: i shouldn't be locking session_state for the whole function but since we are only
-        // receiving files one by one, it should be fine. Shouldn't be locking for the whole
-        // function if we are going to receive multiple files at the same time.
-
-        let (file_id, path, sender) = {
-            let mut session = session_state.lock().await;
-            if session.receive_session.is_none() {
-                return Err((
-                    StatusCode::INTERNAL_SERVER_ERROR,
-                    "Call to /send without requesting a send".into(),
-                ));
+        // files of a session are received in parallel, the server state is only locked to look up
+        // the session, the state of the file is kept in the session itself
+        let (receive_session, sender) = {
+            let session = session_state.lock().await;
+            let receive_session = session.find_session(
+                params.session_id.as_deref(),
+                addr.ip().to_canonical(),
+                &params.file_id,
+                &params.token,
+            );
+            match receive_session {
+                Some(receive_session) => (receive_session, session.server_tx.clone()),
+                None if params.session_id.is_some() => {
+                    return Err((StatusCode::FORBIDDEN, "Invalid session id".into()))
+                }
+                None if session.sessions.is_empty() => {
+                    return Err((
+                        StatusCode::INTERNAL_SERVER_ERROR,
+                        "Call to /send without requesting a send".into(),
+                    ))
+                }
+                None if session.sessions.values().any(|active_session| {
+                    active_session
+                        .receive_session
+                        .files
+                        .contains_key(&params.file_id)
+                }) =>
+                {
+                    return Err((StatusCode::FORBIDDEN, "Invalid token or IP address".into()))
+                }
+                None => {
+                    return Err((
+                        StatusCode::INTERNAL_SERVER_ERROR,
+                        format!("Call to /send with unknown file id {}", params.file_id),
+                    ))
+                }
             }
+        };
 
-            let _ = session
-                .server_tx
-                .send(ServerMessage::SendFileRequest((params.file_id.clone(), 0)));
-
-            if !session
-                .receive_session
-                .as_ref()
-                .unwrap()
-                .files
-                .contains_key(&params.file_id)
-            {
-                return Err((
-                    StatusCode::INTERNAL_SERVER_ERROR,
-                    format!("Call to /send with unknown file id {}", params.file_id),
-                ));
-            }
+        let Some(file_info) = receive_session.files.get(&params.file_id) else {
+            return Err((
+                StatusCode::INTERNAL_SERVER_ERROR,
+                format!("Call to /send with unknown file id {}", params.file_id),
+            ));
+        };
 
-            let receive_session = session.receive_session.as_mut().unwrap();
-            receive_session.status = ReceiveStatus::Receiving;
+        if !receive_session.is_authorized(addr.ip().to_canonical(), &params.file_id, &params.token)
+        {
+            return Err((StatusCode::FORBIDDEN, "Invalid token or IP address".into()));
+        }
 
-            let file_id = params.file_id.clone();
-            let path = Path::new(&receive_session.destination_directory)
-                .join(&receive_session.files[&params.file_id].file_name);
-            (file_id, path, session.server_tx.clone())
-        };
+        if !receive_session.start_file(&params.file_id) {
+            return Err((
+                StatusCode::CONFLICT,
+                format!("File {} was already sent", params.file_id),
+            ));
+        }
 
-        let result = stream_to_file(path, file_stream, file_id.clone(), sender).await;
+        let file_id = params.file_id.clone();
+        let file_size = file_info.size;
+        let path = receive_session
+            .destination_directory
+            .join(&file_info.file_name);
+        let session_id = receive_session.session_id.clone();
+        let _ = sender.send(ServerMessage::SendFileRequest((
+            session_id.clone(),
+            file_id.clone(),
+            0,
+        )));
 
-        let mut session = session_state.lock().await;
-        if session.receive_session.is_none() {
+        let (mut outcome, part_path) =
+            match resolve_file_path(path, config.collision_policy, &receive_session).await {
+                FileOutcome::Skipped => {
+                    // the sender doesn't know we skipped the file, read the body so it doesn't
+                    // error, but no more of it than was declared
+                    let mut received = 0;
+                    let drained = file_stream
+                        .map_err(|_| ())
+                        .try_for_each(|chunk| {
+                            received += chunk.len();
+                            futures::future::ready(if received > file_size {
+                                Err(())
+                            } else {
+                                Ok(())
+                            })
+                        })
+                        .await;
+                    match drained {
+                        Err(()) if received > file_size => (
+                            FileOutcome::Failed(format!(
+                                "Received more than the expected {} bytes",
+                                file_size
+                            )),
+                            None,
+                        ),
+                        _ => (FileOutcome::Skipped, None),
+                    }
+                }
+                FileOutcome::Failed(err) => (FileOutcome::Failed(err), None),
+                outcome => match create_partial_file(outcome.path().unwrap()).await {
+                    Err(err) => (FileOutcome::Failed(err.to_string()), None),
+                    Ok((part_path, file)) => {
+                        // the body is dropped when the session is cancelled, which stops the upload
+                        let result = tokio::select! {
+                            result = stream_to_file(
+                                file,
+                                file_size,
+                                file_stream,
+                                &receive_session,
+                                file_id.clone(),
+                                sender.clone(),
+                            ) => result,
+                            _ = receive_session.cancelled() => Err(io::Error::new(
+                                io::ErrorKind::Interrupted,
+                                "Session was cancelled",
+                            )),
+                        };
+                        match result {
+                            Ok(()) => (outcome, Some(part_path)),
+                            Err(err) => {
+                                discard_partial_file(&part_path, config.keep_partial_files).await;
+                                (FileOutcome::Failed(err.to_string()), None)
+                            }
+                        }
+                    }
+                },
+            };
+
+        if receive_session.is_cancelled() {
+            if let Some(part_path) = part_path {
+                discard_partial_file(&part_path, config.keep_partial_files).await;
+            }
             // TODO(notjedi): should i return Ok(()) here?
             return Err((
                 StatusCode::INTERNAL_SERVER_ERROR,
                 "Session might have been cancelled while receiving file".into(),
             ));
         }
-        let receive_session = session.receive_session.as_mut().unwrap();
-
-        receive_session
-            .file_status
-            .entry(file_id)
-            .and_modify(|file_status| {
-                *file_status = if result.is_ok() {
-                    ReceiveStatus::Finished
-                } else {
-                    ReceiveStatus::FinishedWithErrors
+
+        // only move the file to its final name once it has been received completely
+        if let (Some(path), Some(part_path)) = (outcome.path(), part_path) {
+            if let Err(err) = tokio::fs::rename(&part_path, path).await {
+                discard_partial_file(&part_path, config.keep_partial_files).await;
+                outcome = FileOutcome::Failed(err.to_string());
+            }
+        }
+        let _ = sender.send(ServerMessage::FileReceived((
+            session_id,
+            file_id.clone(),
+            outcome.clone(),
+        )));
+
+        if receive_session.finish_file(&file_id, outcome.is_failed()) {
+            // TODO: send message to bin crate before removing the session
+            // frees the slot of the session for the next queued request
+            session_state
+                .lock()
+                .await
+                .sessions
+                .remove(&receive_session.session_id);
+        }
+
+        match outcome {
+            FileOutcome::Failed(err) if config.collision_policy == CollisionPolicy::Fail => {
+                Err((StatusCode::CONFLICT, err))
+            }
+            FileOutcome::Failed(err) => Err((StatusCode::INTERNAL_SERVER_ERROR, err)),
+            _ => Ok(()),
+        }
+    }
+}
+
+async fn serve(
+    listener: std::net::TcpListener,
+    app: Router,
+    tls_config: Option<RustlsConfig>,
+) -> io::Result<()> {
+    let make_service = app.into_make_service_with_connect_info::<SocketAddr>();
+    match tls_config {
+        Some(tls_config) => {
+            axum_server::from_tcp_rustls(listener, tls_config)
+                .serve(make_service)
+                .await
+        }
+        None => axum_server::from_tcp(listener).serve(make_service).await,
+    }
+}
+
+// listening on [::] also accepts IPv4 connections, whatever the system default is. their
+// addresses show up as IPv4-mapped IPv6 addresses and are canonicalized in the handlers
+fn bind_listener(addr: SocketAddr) -> io::Result<std::net::TcpListener> {
+    let socket = Socket::new(Domain::for_address(addr), Type::STREAM, None)?;
+    if addr.is_ipv6() {
+        socket.set_only_v6(false)?;
+    }
+    socket.set_reuse_address(true)?;
+    socket.bind(&addr.into())?;
+    socket.listen(1024)?;
+    socket.set_nonblocking(true)?;
+    Ok(socket.into())
+}
+
+// files are received into a hidden file next to the final one, named so that it can't be an
+// existing file of the user, e.g. a `notes.txt.part` that receiving `notes.txt` would clobber
+async fn create_partial_file(path: &Path) -> io::Result<(PathBuf, File)> {
+    // file names of files sent as part of a folder contain the sub directories
+    if let Some(parent) = path.parent() {
+        tokio::fs::create_dir_all(parent).await?;
+    }
+
+    loop {
+        let mut file_name = OsString::from(".");
+        file_name.push(path.file_name().unwrap_or_default());
+        file_name.push(format!(".{}.part", Uuid::new_v4()));
+        let part_path = path.with_file_name(file_name);
+
+        let file = OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .open(&part_path)
+            .await;
+        match file {
+            Ok(file) => return Ok((part_path, file)),
+            Err(err) if err.kind() == io::ErrorKind::AlreadyExists => continue,
+            Err(err) => return Err(err),
+        }
+    }
+}
+
+async fn discard_partial_file(part_path: &Path, keep_partial_files: bool) {
+    if keep_partial_files {
+        info!("keeping partial file {}", part_path.display());
+    } else if let Err(err) = tokio::fs::remove_file(part_path).await {
+        trace!("failed to remove {}: {}", part_path.display(), err);
+    }
+}
+
+/// Picks the path a received file is written to when `path` already exists.
+///
+/// Paths are claimed through `receive_session` before they are written to, in a set shared by all
+/// sessions of the server. A path claimed by another file of a session that is still around is
+/// always renamed, so files with the same name sent together or at the same time all arrive,
+/// whatever the collision policy.
+async fn resolve_file_path(
+    path: PathBuf,
+    collision_policy: CollisionPolicy,
+    receive_session: &ReceiveSession,
+) -> FileOutcome {
+    if !tokio::fs::try_exists(&path).await.unwrap_or(false) && receive_session.claim_path(&path) {
+        return FileOutcome::Saved(path);
+    }
+
+    let collision_policy = if receive_session.is_path_claimed(&path) {
+        CollisionPolicy::Rename
+    } else {
+        collision_policy
+    };
+    match collision_policy {
+        CollisionPolicy::Overwrite if receive_session.claim_path(&path) => {
+            FileOutcome::Overwritten(path)
+        }
+        CollisionPolicy::Skip => FileOutcome::Skipped,
+        CollisionPolicy::Fail => FileOutcome::Failed(format!("{} already exists", path.display())),
+        // another file claimed the path since it was checked
+        CollisionPolicy::Overwrite | CollisionPolicy::Rename => {
+            // same naming scheme as the official app: `file.txt` -> `file (1).txt`
+            let stem = path
+                .file_stem()
+                .map(|stem| stem.to_string_lossy().into_owned())
+                .unwrap_or_default();
+            let extension = path
+                .extension()
+                .map(|extension| format!(".{}", extension.to_string_lossy()))
+                .unwrap_or_default();
+
+            let mut counter = 1;
+            loop {
+                let renamed_path =
+                    path.with_file_name(format!("{} ({}){}", stem, counter, extension));
+                if !tokio::fs::try_exists(&renamed_path).await.unwrap_or(false)
+                    && receive_session.claim_path(&renamed_path)
+                {
+                    return FileOutcome::Renamed(renamed_path);
                 }
-            });
-
-        let all_finished = receive_session.files.iter().all(|(file_status_id, _)| {
-            receive_session.file_status[file_status_id] == ReceiveStatus::Finished
-                || receive_session.file_status[file_status_id] == ReceiveStatus::FinishedWithErrors
-        });
-        // TODO(notjedi): do i need to loop over everything and set the status?
-        if all_finished {
-            // TODO: add support for FinishedWithErrors and send message to bin crate before
-            // setting receive_session to None
-            receive_session.status = ReceiveStatus::Finished;
-            session.receive_session = None;
+                counter += 1;
+            }
         }
-        Ok(())
     }
 }
 
 // taken and modified from: https://github.com/tokio-rs/axum/blob/main/examples/stream-to-file/src/main.rs
 async fn stream_to_file<S, E>(
-    path: PathBuf,
+    file: File,
+    expected_size: usize,
     stream: S,
+    receive_session: &ReceiveSession,
     file_id: String,
     sender: Sender<ServerMessage>,
 ) -> std::io::Result<()>
@@ -244,27 +987,39 @@ where
     S: Stream<Item = Result<Bytes, E>>,
     E: Into<BoxError>, // BoxError is just - Box<dyn std::error::Error + Send + Sync>
 {
-    let body_with_io_error = stream.map_err(|err| io::Error::new(io::ErrorKind::Other, err));
+    let body_with_io_error = stream.map_err(io::Error::other);
     let body_reader = StreamReader::new(body_with_io_error);
     futures::pin_mut!(body_reader);
 
-    let file = File::create(path).await?;
     let mut file_buf = BufWriter::with_capacity(16384, file);
 
     // read 1024 * 16 bytes on each read call
     // can i directly write to the file buffer? rn we are copying data to a buf and writing that to the file
     let mut buf = [0u8; 16384];
+    let mut received = 0;
     loop {
         match body_reader.read(&mut buf[..]).await {
             Ok(0) => {
                 break;
             }
             Ok(len) => {
-                // TODO: assert len(read) == len(written)
-                // TODO: don't unwrap
+                // the declared size is what the receive decision and the policy were based on,
+                // stop before writing anything past it
+                if received + len > expected_size {
+                    return Err(std::io::Error::new(
+                        std::io::ErrorKind::InvalidData,
+                        format!("Received more than the expected {} bytes", expected_size),
+                    ));
+                }
                 // TODO: no clones
-                let _ = file_buf.write(&buf[0..len]).await.unwrap();
-                let _ = sender.send(ServerMessage::SendFileRequest((file_id.clone(), len)));
+                file_buf.write_all(&buf[0..len]).await?;
+                received += len;
+                receive_session.touch();
+                let _ = sender.send(ServerMessage::SendFileRequest((
+                    receive_session.session_id.clone(),
+                    file_id.clone(),
+                    len,
+                )));
             }
             Err(_) => {
                 return Err(std::io::Error::new(
@@ -274,5 +1029,15 @@ where
             }
         }
     }
+
+    if received != expected_size {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::InvalidData,
+            format!("Received {} bytes, expected {}", received, expected_size),
+        ));
+    }
+
+    file_buf.flush().await?;
+    file_buf.get_ref().sync_all().await?;
     Ok(())
 }
@@ -0,0 +1,121 @@
+use std::{
+    io,
+    net::{IpAddr, SocketAddr},
+    sync::Arc,
+    time::Duration,
+};
+
+use futures::StreamExt;
+use tokio::net::lookup_host;
+use tracing::{debug, info};
+
+use crate::{
+    device_scanner::validate_device_info, Client, DeviceInfo, Error, PeerRegistry, Result,
+};
+
+/// Time between probes of the static peers.
+pub const DEFAULT_STATIC_PEER_INTERVAL: Duration = Duration::from_secs(10);
+// static peers are few, probing them all at once is fine
+const PROBE_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// Peers added by address, for segmented networks that neither multicast nor subnet sweeps
+/// reach. Created with [`DeviceScanner::static_peers`](crate::DeviceScanner::static_peers),
+/// peers answering their info request are added to the registry of the scanner and never expire.
+pub struct StaticPeers {
+    client: Arc<Client>,
+    registry: PeerRegistry,
+    peers: Vec<String>,
+    port: u16,
+    interval: Duration,
+}
+
+impl StaticPeers {
+    pub(crate) fn new(
+        client: Client,
+        registry: PeerRegistry,
+        peers: Vec<String>,
+        port: u16,
+    ) -> Self {
+        Self {
+            client: Arc::new(client),
+            registry,
+            peers,
+            port,
+            interval: DEFAULT_STATIC_PEER_INTERVAL,
+        }
+    }
+
+    pub fn with_interval(mut self, interval: Duration) -> Self {
+        self.interval = interval;
+        self
+    }
+
+    /// Probes every peer once, returns the number of peers that answered.
+    pub async fn probe(&self) -> usize {
+        let found = futures::stream::iter(self.peers.clone())
+            .map(|peer| self.probe_peer(peer))
+            .buffer_unordered(self.peers.len().max(1))
+            .filter_map(|device_info| async move { device_info })
+            .map(|device_info| self.registry.upsert_static(device_info))
+            .count()
+            .await;
+        info!("{} of {} static peer(s) answered", found, self.peers.len());
+        found
+    }
+
+    /// Probes every interval, so peers coming online later show up, runs until the task is
+    /// dropped.
+    pub async fn run(self) {
+        let mut interval = tokio::time::interval(self.interval);
+        loop {
+            interval.tick().await;
+            self.probe().await;
+        }
+    }
+
+    async fn probe_peer(&self, peer: String) -> Option<DeviceInfo> {
+        let probe = async {
+            let addr = self.resolve(&peer).await?;
+            self.client.fetch_info(addr).await
+        };
+
+        match tokio::time::timeout(PROBE_TIMEOUT, probe).await {
+            // peers are keyed by fingerprint, which v1 peers don't have
+            Ok(Ok(device_info)) if validate_device_info(&device_info).is_ok() => Some(device_info),
+            Ok(Ok(_)) => {
+                debug!("static peer {} didn't send a fingerprint", peer);
+                None
+            }
+            Ok(Err(err)) => {
+                debug!("probing static peer {} failed: {}", peer, err);
+                None
+            }
+            Err(_) => {
+                debug!("static peer {} didn't answer", peer);
+                None
+            }
+        }
+    }
+
+    // `peer` is `host` or `host:port`, hosts are looked up again on every probe in case their
+    // address changed
+    async fn resolve(&self, peer: &str) -> Result<SocketAddr> {
+        if let Ok(ip) = peer.parse::<IpAddr>() {
+            return Ok(SocketAddr::new(ip, self.port));
+        }
+        let has_port = peer
+            .rsplit_once(':')
+            .is_some_and(|(_, port)| port.parse::<u16>().is_ok());
+        let addr = if has_port {
+            lookup_host(peer).await?.next()
+        } else {
+            lookup_host((peer, self.port)).await?.next()
+        };
+        addr.ok_or_else(|| {
+            Error::Io(io::Error::new(
+                io::ErrorKind::NotFound,
+                format!("no address found for {}", peer),
+            ))
+        })
+    }
+}
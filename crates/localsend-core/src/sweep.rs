@@ -0,0 +1,121 @@
+use std::{
+    net::{Ipv4Addr, SocketAddr},
+    sync::Arc,
+    time::Duration,
+};
+
+use futures::StreamExt;
+use reqwest::StatusCode;
+use tracing::{debug, info};
+
+use crate::{
+    device_scanner::validate_device_info, interfaces, Client, DeviceInfo, Error, NetworkInterface,
+    PeerRegistry, Protocol, SessionError,
+};
+
+#[derive(Clone, Debug)]
+pub struct SweepConfig {
+    /// How many hosts are probed at the same time.
+    pub concurrency: usize,
+    /// How long to wait for a host to answer before moving on.
+    pub timeout: Duration,
+    /// Time between sweeps, has to be shorter than the peer ttl for found devices to stay
+    /// around, see [`DeviceScanner::with_registry`](crate::DeviceScanner::with_registry).
+    pub interval: Duration,
+    /// Subnets with a shorter prefix are narrowed down to the /24 around our address, sweeping a
+    /// /16 would take forever. Values below 16 are treated as 16.
+    pub max_prefix_len: u32,
+}
+
+impl Default for SweepConfig {
+    fn default() -> Self {
+        Self {
+            concurrency: 64,
+            timeout: Duration::from_millis(500),
+            interval: Duration::from_secs(20),
+            max_prefix_len: 22,
+        }
+    }
+}
+
+/// Finds devices by probing every host on the subnets of the scanner's interfaces, for networks
+/// that drop multicast. Created with
+/// [`DeviceScanner::subnet_sweeper`](crate::DeviceScanner::subnet_sweeper) and feeds the same
+/// registry.
+pub struct SubnetSweeper {
+    client: Arc<Client>,
+    registry: PeerRegistry,
+    interfaces: Vec<NetworkInterface>,
+    port: u16,
+    protocol: Protocol,
+    config: SweepConfig,
+}
+
+impl SubnetSweeper {
+    pub(crate) fn new(
+        client: Client,
+        registry: PeerRegistry,
+        interfaces: Vec<NetworkInterface>,
+        port: u16,
+        protocol: Protocol,
+        config: SweepConfig,
+    ) -> Self {
+        Self {
+            client: Arc::new(client),
+            registry,
+            interfaces,
+            port,
+            protocol,
+            config,
+        }
+    }
+
+    /// Probes every host once, returns the number of devices found.
+    pub async fn sweep(&self) -> usize {
+        let hosts = interfaces::subnet_hosts(&self.interfaces, self.config.max_prefix_len);
+        debug!("sweeping {} hosts", hosts.len());
+
+        let found = futures::stream::iter(hosts)
+            .map(|ip| self.probe(ip))
+            .buffer_unordered(self.config.concurrency.max(1))
+            .filter_map(|device_info| async move { device_info })
+            .map(|device_info| self.registry.upsert(device_info))
+            .count()
+            .await;
+        info!("subnet sweep found {} device(s)", found);
+        found
+    }
+
+    /// Sweeps every interval, runs until the task is dropped.
+    pub async fn run(self) {
+        let mut interval = tokio::time::interval(self.config.interval);
+        loop {
+            interval.tick().await;
+            self.sweep().await;
+        }
+    }
+
+    async fn probe(&self, ip: Ipv4Addr) -> Option<DeviceInfo> {
+        let addr = SocketAddr::from((ip, self.port));
+        let probe = async {
+            // registering also tells the peer about us, v1 peers only know about info
+            match self.client.register(addr, self.protocol).await {
+                Err(Error::Session(SessionError::UnexpectedStatus(StatusCode::NOT_FOUND))) => {
+                    self.client.fetch_info(addr).await
+                }
+                result => result,
+            }
+        };
+
+        match tokio::time::timeout(self.config.timeout, probe).await {
+            // peers are keyed by fingerprint, which v1 peers don't have
+            Ok(Ok(device_info)) if validate_device_info(&device_info).is_ok() => Some(device_info),
+            Ok(Ok(_)) => None,
+            Ok(Err(err)) => {
+                debug!("probing {} failed: {}", addr, err);
+                None
+            }
+            Err(_) => None,
+        }
+    }
+}
@@ -0,0 +1,179 @@
+use std::{
+    net::IpAddr,
+    sync::{Arc, Mutex},
+    time::SystemTime,
+};
+
+use rustls::{
+    client::{ServerCertVerified, ServerCertVerifier},
+    server::{ClientHello, ResolvesServerCert},
+    sign::{self, CertifiedKey},
+    Certificate, ClientConfig, PrivateKey, ServerConfig, ServerName,
+};
+
+use crate::{certificate_fingerprint, Error, Identity, KnownPeers, Result};
+
+/// Builds the TLS config of the server from the certificate of this device.
+///
+/// Dart's `HttpClient`, and with it the official app, sends the IP address of the peer as the
+/// SNI hostname, which RFC 6066 forbids (https://github.com/dart-lang/sdk/issues/49183). Since
+/// 0.21.12 rustls treats such a hello as if it had no SNI at all, older versions refuse it, so the
+/// certificate is resolved without looking at the server name.
+pub(crate) fn server_config(identity: &Identity) -> Result<ServerConfig> {
+    let key_der = rustls_pemfile::pkcs8_private_keys(&mut identity.key_pem().as_bytes())?
+        .into_iter()
+        .next()
+        .ok_or_else(|| Error::Tls("no private key found in pem".into()))?;
+    let signing_key = sign::any_supported_type(&PrivateKey(key_der))
+        .map_err(|err| Error::Tls(err.to_string()))?;
+    let certified_key =
+        CertifiedKey::new(vec![Certificate(identity.cert_der().to_vec())], signing_key);
+
+    let mut config = ServerConfig::builder()
+        .with_safe_defaults()
+        .with_no_client_auth()
+        .with_cert_resolver(Arc::new(IdentityCertResolver(Arc::new(certified_key))));
+    config.alpn_protocols = vec![b"h2".to_vec(), b"http/1.1".to_vec()];
+    Ok(config)
+}
+
+/// Serves the certificate of this device regardless of the server name the client asked for.
+struct IdentityCertResolver(Arc<CertifiedKey>);
+
+impl ResolvesServerCert for IdentityCertResolver {
+    fn resolve(&self, _client_hello: ClientHello) -> Option<Arc<CertifiedKey>> {
+        Some(self.0.clone())
+    }
+}
+
+/// Verifies the certificate of a peer against its fingerprint, pinning the peer on first use.
+///
+/// Peers use self signed certificates, so there is no chain to verify. The fingerprint is the
+/// hash of the certificate, a peer presenting a certificate that doesn't hash to the fingerprint
+/// it claimed is refused, which also covers a certificate changing under a pinned fingerprint.
+pub(crate) struct PinningVerifier {
+    known_peers: Arc<KnownPeers>,
+    fingerprint: String,
+    alias: String,
+    addr: IpAddr,
+    mismatch: Mutex<Option<Error>>,
+}
+
+impl PinningVerifier {
+    pub(crate) fn new(
+        known_peers: Arc<KnownPeers>,
+        fingerprint: String,
+        alias: String,
+        addr: IpAddr,
+    ) -> Self {
+        Self {
+            known_peers,
+            fingerprint,
+            alias,
+            addr,
+            mismatch: Mutex::new(None),
+        }
+    }
+
+    /// The fingerprint mismatch that failed the last handshake, if any. rustls only lets us
+    /// return a string error, this keeps the details around for the caller.
+    pub(crate) fn take_mismatch(&self) -> Option<Error> {
+        self.mismatch.lock().unwrap().take()
+    }
+
+    pub(crate) fn client_config(self: Arc<Self>) -> ClientConfig {
+        ClientConfig::builder()
+            .with_safe_defaults()
+            .with_custom_certificate_verifier(self)
+            .with_no_client_auth()
+    }
+}
+
+impl ServerCertVerifier for PinningVerifier {
+    fn verify_server_cert(
+        &self,
+        end_entity: &Certificate,
+        _intermediates: &[Certificate],
+        _server_name: &ServerName,
+        _scts: &mut dyn Iterator<Item = &[u8]>,
+        _ocsp_response: &[u8],
+        _now: SystemTime,
+    ) -> std::result::Result<ServerCertVerified, rustls::Error> {
+        let cert_hash = certificate_fingerprint(&end_entity.0);
+        if !cert_hash.eq_ignore_ascii_case(&self.fingerprint) {
+            *self.mismatch.lock().unwrap() = Some(Error::FingerprintMismatch {
+                fingerprint: self.fingerprint.clone(),
+                cert_hash,
+            });
+            return Err(rustls::Error::General(format!(
+                "certificate doesn't match the fingerprint {}",
+                self.fingerprint
+            )));
+        }
+
+        self.known_peers
+            .verify(&self.fingerprint, &self.alias, self.addr)
+            .map_err(|err| rustls::Error::General(err.to_string()))?;
+        Ok(ServerCertVerified::assertion())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A verifier for a client that never gets to see the certificate.
+    struct NoVerifier;
+
+    impl ServerCertVerifier for NoVerifier {
+        fn verify_server_cert(
+            &self,
+            _end_entity: &Certificate,
+            _intermediates: &[Certificate],
+            _server_name: &ServerName,
+            _scts: &mut dyn Iterator<Item = &[u8]>,
+            _ocsp_response: &[u8],
+            _now: SystemTime,
+        ) -> std::result::Result<ServerCertVerified, rustls::Error> {
+            Ok(ServerCertVerified::assertion())
+        }
+    }
+
+    // the client hello the dart side sends, rustls won't put an ip address in the sni itself
+    fn client_hello_with_ip_sni() -> Vec<u8> {
+        let config = ClientConfig::builder()
+            .with_safe_defaults()
+            .with_custom_certificate_verifier(Arc::new(NoVerifier))
+            .with_no_client_auth();
+        let server_name = ServerName::try_from("localhost").unwrap();
+        let mut client = rustls::ClientConnection::new(Arc::new(config), server_name).unwrap();
+        let mut hello = Vec::new();
+        client.write_tls(&mut hello).unwrap();
+
+        // same length, so none of the length prefixes change
+        let start = hello
+            .windows(b"localhost".len())
+            .position(|window| window == b"localhost")
+            .unwrap();
+        hello[start..start + b"localhost".len()].copy_from_slice(b"127.0.0.1");
+        hello
+    }
+
+    #[test]
+    fn server_answers_a_hello_with_an_ip_address_as_sni() {
+        let identity = Identity::generate().unwrap();
+        let config = server_config(&identity).unwrap();
+        let mut server = rustls::ServerConnection::new(Arc::new(config)).unwrap();
+
+        let hello = client_hello_with_ip_sni();
+        server.read_tls(&mut hello.as_slice()).unwrap();
+        server.process_new_packets().unwrap();
+        assert_eq!(server.server_name(), None);
+
+        let mut response = Vec::new();
+        server.write_tls(&mut response).unwrap();
+        // a handshake record carrying the server hello, not an alert
+        assert_eq!(response[0], 0x16);
+        assert_eq!(response[5], 0x02);
+    }
+}
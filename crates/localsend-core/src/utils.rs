@@ -1,25 +1,21 @@
-use std::net::IpAddr;
+use std::{net::IpAddr, path::PathBuf};
 
 use network_interface::{NetworkInterface, NetworkInterfaceConfig};
-use rcgen::{Certificate, CertificateParams, DnType, DnValue};
+use rcgen::{Certificate, CertificateParams, DnType, DnValue, RcgenError};
 
-pub(crate) fn get_device_ip_addr() -> Option<IpAddr> {
-    for network_interface in NetworkInterface::show().unwrap_or(vec![]).iter() {
-        match network_interface.addr.first() {
-            Some(addr) => {
-                if addr.ip().is_loopback() {
-                    continue;
-                } else {
-                    return Some(addr.ip());
-                }
-            }
-            None => continue,
-        };
-    }
-    None
+pub(crate) fn get_local_ip_addrs() -> Vec<IpAddr> {
+    NetworkInterface::show()
+        .unwrap_or(vec![])
+        .iter()
+        .flat_map(|network_interface| network_interface.addr.iter().map(|addr| addr.ip()))
+        .collect()
 }
 
-pub fn generate_tls_cert() -> Certificate {
+pub(crate) fn default_destination_directory() -> PathBuf {
+    dirs::download_dir().unwrap_or_else(|| PathBuf::from("."))
+}
+
+pub fn generate_tls_cert() -> Result<Certificate, RcgenError> {
     let mut params: CertificateParams = Default::default();
     params.distinguished_name.push(
         DnType::CommonName,
@@ -40,5 +36,5 @@ pub fn generate_tls_cert() -> Certificate {
     params
         .distinguished_name
         .push(DnType::CountryName, "".to_string());
-    Certificate::from_params(params).unwrap()
+    Certificate::from_params(params)
 }
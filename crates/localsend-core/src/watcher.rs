@@ -0,0 +1,181 @@
+use std::time::Duration;
+
+use futures::Stream;
+use tokio::{sync::broadcast, time::Interval};
+use tracing::{debug, warn};
+
+use crate::{list_interfaces, InterfaceFilter, NetworkInterface};
+
+/// How often interfaces are listed where address changes can't be subscribed to.
+pub const DEFAULT_POLL_INTERVAL: Duration = Duration::from_secs(5);
+const EVENT_CHANNEL_CAPACITY: usize = 64;
+// addresses change in bursts when a network comes up, IPv6 ones only show up once duplicate
+// address detection is done
+const SETTLE_DELAY: Duration = Duration::from_millis(500);
+
+#[derive(Clone, Debug)]
+pub enum NetworkEvent {
+    AddressAdded(NetworkInterface),
+    AddressRemoved(NetworkInterface),
+}
+
+/// Watches the addresses of the interfaces of this device, so discovery and the server can
+/// follow the device from one network to the next.
+///
+/// Cloning the watcher is cheap and all clones share the same events.
+#[derive(Clone)]
+pub struct NetworkWatcher {
+    event_tx: broadcast::Sender<NetworkEvent>,
+    poll_interval: Duration,
+}
+
+impl Default for NetworkWatcher {
+    fn default() -> Self {
+        Self::new(DEFAULT_POLL_INTERVAL)
+    }
+}
+
+impl NetworkWatcher {
+    /// Uses netlink on Linux, elsewhere or if netlink is unavailable the interfaces are listed
+    /// every `poll_interval`.
+    pub fn new(poll_interval: Duration) -> Self {
+        let (event_tx, _) = broadcast::channel(EVENT_CHANNEL_CAPACITY);
+        Self {
+            event_tx,
+            poll_interval,
+        }
+    }
+
+    /// Emits an event for every address added or removed, runs until the task is dropped.
+    pub async fn run(self) {
+        let mut interfaces = list_interfaces(&InterfaceFilter::all());
+        let mut changes = Changes::subscribe(self.poll_interval);
+        loop {
+            changes.next().await;
+            tokio::time::sleep(SETTLE_DELAY).await;
+            changes.drain();
+
+            let new_interfaces = list_interfaces(&InterfaceFilter::all());
+            // removals first, so a rebind to the new address of an interface sees the old one gone
+            let removed = interfaces
+                .iter()
+                .filter(|interface| !new_interfaces.contains(interface))
+                .cloned()
+                .map(NetworkEvent::AddressRemoved);
+            let added = new_interfaces
+                .iter()
+                .filter(|interface| !interfaces.contains(interface))
+                .cloned()
+                .map(NetworkEvent::AddressAdded);
+            for event in removed.chain(added) {
+                debug!("{:?}", event);
+                let _ = self.event_tx.send(event);
+            }
+            interfaces = new_interfaces;
+        }
+    }
+
+    /// Stream of address changes after the call.
+    pub fn events(&self) -> impl Stream<Item = NetworkEvent> {
+        futures::stream::unfold(self.event_tx.subscribe(), |mut event_rx| async move {
+            loop {
+                match event_rx.recv().await {
+                    Ok(event) => return Some((event, event_rx)),
+                    // the next event makes consumers look at the interfaces again anyway
+                    Err(broadcast::error::RecvError::Lagged(_)) => continue,
+                    Err(broadcast::error::RecvError::Closed) => return None,
+                }
+            }
+        })
+    }
+}
+
+// tells when the interfaces may have changed, without saying how
+enum Changes {
+    #[cfg(target_os = "linux")]
+    Netlink(netlink::RouteSocket),
+    Poll(Interval),
+}
+
+impl Changes {
+    fn subscribe(poll_interval: Duration) -> Self {
+        #[cfg(target_os = "linux")]
+        match netlink::RouteSocket::subscribe() {
+            Ok(socket) => return Self::Netlink(socket),
+            Err(err) => warn!(
+                "can't subscribe to address changes, polling instead: {}",
+                err
+            ),
+        }
+        Self::poll(poll_interval)
+    }
+
+    fn poll(poll_interval: Duration) -> Self {
+        let mut interval = tokio::time::interval(poll_interval);
+        interval.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+        Self::Poll(interval)
+    }
+
+    async fn next(&mut self) {
+        match self {
+            #[cfg(target_os = "linux")]
+            Self::Netlink(socket) => {
+                if let Err(err) = socket.recv().await {
+                    warn!("lost netlink subscription, polling instead: {}", err);
+                    *self = Self::poll(DEFAULT_POLL_INTERVAL);
+                }
+            }
+            Self::Poll(interval) => {
+                interval.tick().await;
+            }
+        }
+    }
+
+    // skips the notifications of a burst that were queued up while it settled
+    fn drain(&mut self) {
+        #[cfg(target_os = "linux")]
+        if let Self::Netlink(socket) = self {
+            socket.drain();
+        }
+    }
+}
+
+#[cfg(target_os = "linux")]
+mod netlink {
+    use std::io;
+
+    use netlink_sys::{protocols::NETLINK_ROUTE, AsyncSocket, AsyncSocketExt, SocketAddr};
+
+    // multicast groups of rtnetlink, from linux/rtnetlink.h
+    const RTMGRP_LINK: u32 = 0x1;
+    const RTMGRP_IPV4_IFADDR: u32 = 0x10;
+    const RTMGRP_IPV6_IFADDR: u32 = 0x100;
+    // notifications are only waited on, not parsed, so truncating them is fine
+    const RECV_BUFFER_SIZE: usize = 4096;
+
+    pub(super) struct RouteSocket(netlink_sys::TokioSocket);
+
+    impl RouteSocket {
+        pub(super) fn subscribe() -> io::Result<Self> {
+            let mut socket = netlink_sys::TokioSocket::new(NETLINK_ROUTE)?;
+            socket.socket_mut().bind(&SocketAddr::new(
+                0,
+                RTMGRP_LINK | RTMGRP_IPV4_IFADDR | RTMGRP_IPV6_IFADDR,
+            ))?;
+            Ok(Self(socket))
+        }
+
+        pub(super) async fn recv(&mut self) -> io::Result<()> {
+            let mut buf = Vec::with_capacity(RECV_BUFFER_SIZE);
+            self.0.recv(&mut buf).await
+        }
+
+        pub(super) fn drain(&mut self) {
+            let mut buf = Vec::with_capacity(RECV_BUFFER_SIZE);
+            // the socket is non blocking, this stops once the queue is empty
+            while self.0.socket_ref().recv(&mut buf, 0).is_ok() {
+                buf.clear();
+            }
+        }
+    }
+}
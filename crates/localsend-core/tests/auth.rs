@@ -0,0 +1,203 @@
+mod common;
+
+use std::{
+    collections::HashMap,
+    net::{Ipv4Addr, Ipv6Addr, SocketAddr},
+};
+
+use common::{accept_all, start_server, start_session, upload, ServerOptions, TestServer};
+use localsend_core::{DeviceInfo, FileInfo, FileType, SendRequest, ServerConfig};
+use reqwest::StatusCode;
+
+const CONTENT: &[u8] = b"authorized";
+
+// listens on both stacks, so the same session can be reached from 127.0.0.1 and ::1, and takes
+// two sessions at once
+async fn start_dual_stack_server(port: u16) -> TestServer {
+    let options = ServerOptions {
+        interface_addr: Ipv6Addr::UNSPECIFIED.into(),
+        config: ServerConfig {
+            max_concurrent_sessions: 2,
+            ..Default::default()
+        },
+        ..Default::default()
+    };
+    start_server(port, options, accept_all).await
+}
+
+fn ipv4(port: u16) -> SocketAddr {
+    (Ipv4Addr::LOCALHOST, port).into()
+}
+
+fn ipv6(port: u16) -> SocketAddr {
+    (Ipv6Addr::LOCALHOST, port).into()
+}
+
+async fn cancel(addr: SocketAddr, session_id: Option<&str>) -> StatusCode {
+    let request = match session_id {
+        Some(session_id) => reqwest::Client::new()
+            .post(format!("http://{}/api/localsend/v2/cancel", addr))
+            .query(&[("sessionId", session_id)]),
+        None => reqwest::Client::new().post(format!("http://{}/api/localsend/v1/cancel", addr)),
+    };
+    request.send().await.unwrap().status()
+}
+
+// offers a file the way v1 peers do, with the same file id every time, returns its token
+async fn send_request_v1(addr: SocketAddr, file_name: &str, size: usize) -> String {
+    let file_info = FileInfo {
+        id: "v1-file".into(),
+        size,
+        file_name: file_name.into(),
+        file_type: FileType::Other,
+        sha256: None,
+        preview: None,
+    };
+    let send_request = SendRequest {
+        device_info: DeviceInfo {
+            alias: "client".into(),
+            fingerprint: "client-fingerprint".into(),
+            ..Default::default()
+        },
+        files: HashMap::from([(file_info.id.clone(), file_info)]),
+    };
+    let mut tokens: HashMap<String, String> = reqwest::Client::new()
+        .post(format!("http://{}/api/localsend/v1/send-request", addr))
+        .json(&send_request)
+        .send()
+        .await
+        .unwrap()
+        .json()
+        .await
+        .unwrap();
+    tokens.remove("v1-file").unwrap()
+}
+
+async fn upload_v1(addr: SocketAddr, token: &str, content: &'static [u8]) -> StatusCode {
+    reqwest::Client::new()
+        .post(format!("http://{}/api/localsend/v1/send", addr))
+        .query(&[("fileId", "v1-file"), ("token", token)])
+        .body(content)
+        .send()
+        .await
+        .unwrap()
+        .status()
+}
+
+#[tokio::test]
+async fn uploads_with_a_wrong_token_are_forbidden() {
+    let server = start_dual_stack_server(53471).await;
+    let port = server.addr.port();
+    let (session, file_ids) = start_session(ipv4(port), &["a.txt"], CONTENT.len()).await;
+
+    let status = upload(
+        ipv4(port),
+        &session.session_id,
+        &file_ids[0],
+        "wrong-token",
+        CONTENT,
+    )
+    .await;
+    assert_eq!(status, StatusCode::FORBIDDEN);
+}
+
+#[tokio::test]
+async fn uploads_from_another_address_are_forbidden() {
+    let server = start_dual_stack_server(53472).await;
+    let port = server.addr.port();
+    let (session, file_ids) = start_session(ipv4(port), &["a.txt"], CONTENT.len()).await;
+    let token = &session.files[&file_ids[0]];
+
+    let status = upload(
+        ipv6(port),
+        &session.session_id,
+        &file_ids[0],
+        token,
+        CONTENT,
+    )
+    .await;
+    assert_eq!(status, StatusCode::FORBIDDEN);
+    // the token still works for the sender
+    let status = upload(
+        ipv4(port),
+        &session.session_id,
+        &file_ids[0],
+        token,
+        CONTENT,
+    )
+    .await;
+    assert_eq!(status, StatusCode::OK);
+}
+
+#[tokio::test]
+async fn only_the_sender_can_cancel_its_session() {
+    let server = start_dual_stack_server(53473).await;
+    let port = server.addr.port();
+    let (session, _) = start_session(ipv4(port), &["a.txt"], CONTENT.len()).await;
+
+    assert_eq!(
+        cancel(ipv6(port), Some(&session.session_id)).await,
+        StatusCode::FORBIDDEN
+    );
+    assert_eq!(cancel(ipv6(port), None).await, StatusCode::FORBIDDEN);
+    assert_eq!(
+        cancel(ipv4(port), Some(&session.session_id)).await,
+        StatusCode::OK
+    );
+}
+
+#[tokio::test]
+async fn uploads_to_unknown_sessions_are_forbidden() {
+    let server = start_dual_stack_server(53474).await;
+
+    let status = upload(
+        ipv4(server.addr.port()),
+        "no-session",
+        "a",
+        "token",
+        CONTENT,
+    )
+    .await;
+    assert_eq!(status, StatusCode::FORBIDDEN);
+}
+
+#[tokio::test]
+async fn v1_uploads_go_to_the_session_of_their_token() {
+    let server = start_dual_stack_server(53475).await;
+    let port = server.addr.port();
+    let first_token = send_request_v1(ipv4(port), "first.txt", 5).await;
+    let second_token = send_request_v1(ipv4(port), "second.txt", 6).await;
+
+    assert_eq!(
+        upload_v1(ipv6(port), &second_token, b"second").await,
+        StatusCode::FORBIDDEN
+    );
+    assert_eq!(
+        upload_v1(ipv4(port), &second_token, b"second").await,
+        StatusCode::OK
+    );
+    assert_eq!(
+        upload_v1(ipv4(port), &first_token, b"first").await,
+        StatusCode::OK
+    );
+    for (file_name, content) in [("first.txt", "first"), ("second.txt", "second")] {
+        let received = server.destination_directory.join(file_name);
+        assert_eq!(std::fs::read_to_string(received).unwrap(), content);
+    }
+}
+
+#[tokio::test]
+async fn v1_cancel_is_refused_when_the_session_is_ambiguous() {
+    let server = start_dual_stack_server(53476).await;
+    let port = server.addr.port();
+    let (first_session, _) = start_session(ipv4(port), &["a.txt"], CONTENT.len()).await;
+    start_session(ipv4(port), &["b.txt"], CONTENT.len()).await;
+
+    assert_eq!(cancel(ipv4(port), None).await, StatusCode::CONFLICT);
+    assert_eq!(
+        cancel(ipv4(port), Some(&first_session.session_id)).await,
+        StatusCode::OK
+    );
+    // the other session is the only one left
+    assert_eq!(cancel(ipv4(port), None).await, StatusCode::OK);
+}
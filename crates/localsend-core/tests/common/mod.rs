@@ -0,0 +1,196 @@
+// every test binary uses a different part of this
+#![allow(dead_code)]
+
+use std::{
+    collections::HashMap,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+    path::PathBuf,
+    time::Duration,
+};
+
+use localsend_core::{
+    CancelHandle, Client, DeviceInfo, FileInfo, FileType, Identity, PeerRegistry,
+    PrepareUploadResponse, Protocol, ReceiveDecision, ReceiveHandler, SendRequest, Server,
+    ServerConfig, ServerMessage,
+};
+use reqwest::{Body, StatusCode};
+use tokio::sync::mpsc::{self, UnboundedReceiver};
+use uuid::Uuid;
+
+pub struct TestServer {
+    pub addr: SocketAddr,
+    pub registry: PeerRegistry,
+    pub destination_directory: PathBuf,
+    pub server_rx: UnboundedReceiver<ServerMessage>,
+    pub cancel_handle: CancelHandle,
+}
+
+/// How a test server is set up, a plain http server on localhost by default.
+pub struct ServerOptions {
+    pub interface_addr: IpAddr,
+    /// Replaces `config.protocol`.
+    pub protocol: Protocol,
+    /// Fingerprint the server claims, the one of its certificate if `None`.
+    pub fingerprint: Option<String>,
+    /// The destination directory is replaced by a fresh temporary one.
+    pub config: ServerConfig,
+}
+
+impl Default for ServerOptions {
+    fn default() -> Self {
+        Self {
+            interface_addr: Ipv4Addr::LOCALHOST.into(),
+            protocol: Protocol::Http,
+            fingerprint: None,
+            config: ServerConfig::default(),
+        }
+    }
+}
+
+pub async fn accept_all(send_request: SendRequest) -> ReceiveDecision {
+    ReceiveDecision::Accept {
+        file_ids: send_request.files.into_keys().collect(),
+        destination: None,
+    }
+}
+
+// starts a server and waits until it answers
+pub async fn start_server(
+    port: u16,
+    options: ServerOptions,
+    receive_handler: impl ReceiveHandler + 'static,
+) -> TestServer {
+    let identity = Identity::generate().unwrap();
+    let this_device = DeviceInfo {
+        alias: "server".into(),
+        fingerprint: options
+            .fingerprint
+            .unwrap_or_else(|| identity.fingerprint().into()),
+        port,
+        ..Default::default()
+    };
+    let destination_directory = std::env::temp_dir().join(Uuid::new_v4().to_string());
+    let config = ServerConfig {
+        destination_directory: destination_directory.clone(),
+        protocol: options.protocol,
+        ..options.config
+    };
+    let interface_addr = options.interface_addr;
+    let registry = PeerRegistry::default();
+    let server = Server::new(this_device, identity, interface_addr, port, config)
+        .with_registry(registry.clone());
+    let cancel_handle = server.cancel_handle();
+
+    let (server_tx, server_rx) = mpsc::unbounded_channel();
+    tokio::spawn(async move { server.start_server(server_tx, receive_handler).await });
+
+    let addr = SocketAddr::new(interface_addr, port);
+    let connect_addr = match interface_addr {
+        IpAddr::V4(ip) if ip.is_unspecified() => SocketAddr::from((Ipv4Addr::LOCALHOST, port)),
+        IpAddr::V6(ip) if ip.is_unspecified() => SocketAddr::from((Ipv6Addr::LOCALHOST, port)),
+        _ => addr,
+    };
+    let client = client(options.protocol);
+    for _ in 0..50 {
+        if client.fetch_info(connect_addr).await.is_ok() {
+            break;
+        }
+        tokio::time::sleep(Duration::from_millis(20)).await;
+    }
+
+    TestServer {
+        addr,
+        registry,
+        destination_directory,
+        server_rx,
+        cancel_handle,
+    }
+}
+
+pub fn client(protocol: Protocol) -> Client {
+    Client::new("client".into(), "client-fingerprint".into(), 53317)
+        .unwrap()
+        .with_protocol(protocol)
+}
+
+// a file with `content` to send with the client
+pub fn file_to_send(content: &[u8]) -> PathBuf {
+    let path = std::env::temp_dir().join(format!("{}.txt", Uuid::new_v4()));
+    std::fs::write(&path, content).unwrap();
+    path
+}
+
+// offers files of `size` bytes named `file_names`, the file ids are in the order of the names
+pub async fn prepare_upload(
+    addr: SocketAddr,
+    file_names: &[&str],
+    size: usize,
+) -> (reqwest::Response, Vec<String>) {
+    let file_ids: Vec<String> = file_names
+        .iter()
+        .map(|_| Uuid::new_v4().to_string())
+        .collect();
+    let files: HashMap<String, FileInfo> = file_ids
+        .iter()
+        .zip(file_names)
+        .map(|(file_id, file_name)| {
+            let file_info = FileInfo {
+                id: file_id.clone(),
+                size,
+                file_name: file_name.to_string(),
+                file_type: FileType::Other,
+                sha256: None,
+                preview: None,
+            };
+            (file_id.clone(), file_info)
+        })
+        .collect();
+    let send_request = SendRequest {
+        device_info: DeviceInfo {
+            alias: "client".into(),
+            fingerprint: "client-fingerprint".into(),
+            ..Default::default()
+        },
+        files,
+    };
+
+    let response = reqwest::Client::new()
+        .post(format!("http://{}/api/localsend/v2/prepare-upload", addr))
+        .json(&send_request)
+        .send()
+        .await
+        .unwrap();
+    (response, file_ids)
+}
+
+// like `prepare_upload`, for requests that have to be accepted
+pub async fn start_session(
+    addr: SocketAddr,
+    file_names: &[&str],
+    size: usize,
+) -> (PrepareUploadResponse, Vec<String>) {
+    let (response, file_ids) = prepare_upload(addr, file_names, size).await;
+    assert_eq!(response.status(), StatusCode::OK);
+    (response.json().await.unwrap(), file_ids)
+}
+
+pub async fn upload(
+    addr: SocketAddr,
+    session_id: &str,
+    file_id: &str,
+    token: &str,
+    body: impl Into<Body>,
+) -> StatusCode {
+    reqwest::Client::new()
+        .post(format!("http://{}/api/localsend/v2/upload", addr))
+        .query(&[
+            ("sessionId", session_id),
+            ("fileId", file_id),
+            ("token", token),
+        ])
+        .body(body)
+        .send()
+        .await
+        .unwrap()
+        .status()
+}
@@ -0,0 +1,331 @@
+mod common;
+
+use std::{io, net::SocketAddr, time::Duration};
+
+use common::{accept_all, start_server, start_session, upload, ServerOptions, TestServer};
+use futures::StreamExt;
+use localsend_core::{CollisionPolicy, PrepareUploadResponse, ServerConfig, ServerMessage};
+use reqwest::{Body, StatusCode};
+
+const CHUNK_SIZE: usize = 4096;
+const CHUNKS: usize = 16;
+const FILE_SIZE: usize = CHUNK_SIZE * CHUNKS;
+
+// every file gets its own byte pattern, so mixed up chunks show in the received content
+fn content(index: usize) -> Vec<u8> {
+    (0..FILE_SIZE)
+        .map(|offset| (index * 31 + offset % 251) as u8)
+        .collect()
+}
+
+const USERS_FILE: &[u8] = b"the user's own file";
+
+// starts a server whose destination directory already has `existing.txt`
+async fn start_server_with_existing_file(
+    port: u16,
+    collision_policy: CollisionPolicy,
+) -> TestServer {
+    let options = ServerOptions {
+        config: ServerConfig {
+            collision_policy,
+            ..Default::default()
+        },
+        ..Default::default()
+    };
+    let server = start_server(port, options, accept_all).await;
+    std::fs::create_dir_all(&server.destination_directory).unwrap();
+    std::fs::write(
+        server.destination_directory.join("existing.txt"),
+        USERS_FILE,
+    )
+    .unwrap();
+    server
+}
+
+fn files_in(server: &TestServer) -> usize {
+    std::fs::read_dir(&server.destination_directory)
+        .unwrap()
+        .count()
+}
+
+// uploads `content` in chunks with a pause after each, so parallel uploads interleave
+async fn upload_in_chunks(
+    addr: SocketAddr,
+    session: &PrepareUploadResponse,
+    file_id: &str,
+    content: Vec<u8>,
+) -> StatusCode {
+    let chunks = futures::stream::iter(
+        content
+            .chunks(CHUNK_SIZE)
+            .map(<[u8]>::to_vec)
+            .collect::<Vec<_>>(),
+    )
+    .then(|chunk| async move {
+        tokio::time::sleep(Duration::from_millis(5)).await;
+        Ok::<_, io::Error>(chunk)
+    });
+    let token = &session.files[file_id];
+    upload(
+        addr,
+        &session.session_id,
+        file_id,
+        token,
+        Body::wrap_stream(chunks),
+    )
+    .await
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn parallel_uploads_land_intact() {
+    let mut server = start_server(53431, ServerOptions::default(), accept_all).await;
+    let file_names = ["a.bin", "b.bin", "c.bin", "d.bin"];
+    let (session, file_ids) = start_session(server.addr, &file_names, FILE_SIZE).await;
+
+    let uploads = file_ids
+        .iter()
+        .enumerate()
+        .map(|(index, file_id)| upload_in_chunks(server.addr, &session, file_id, content(index)));
+    let statuses = futures::future::join_all(uploads).await;
+    assert!(statuses.iter().all(|status| *status == StatusCode::OK));
+
+    for (index, file_name) in file_names.iter().enumerate() {
+        let received = std::fs::read(server.destination_directory.join(file_name)).unwrap();
+        assert!(received == content(index), "{} was mixed up", file_name);
+    }
+
+    // every upload started before the first one was done
+    let mut started = 0;
+    while let Ok(server_msg) = server.server_rx.try_recv() {
+        match server_msg {
+            ServerMessage::SendFileRequest((session_id, _, 0)) => {
+                assert_eq!(session_id, session.session_id);
+                started += 1;
+            }
+            ServerMessage::FileReceived(_) => break,
+            _ => {}
+        }
+    }
+    assert_eq!(started, file_names.len());
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn parallel_uploads_with_the_same_name_are_all_kept() {
+    let server = start_server(53432, ServerOptions::default(), accept_all).await;
+    let (session, file_ids) =
+        start_session(server.addr, &["same.bin", "same.bin"], FILE_SIZE).await;
+
+    let uploads = file_ids
+        .iter()
+        .enumerate()
+        .map(|(index, file_id)| upload_in_chunks(server.addr, &session, file_id, content(index)));
+    let statuses = futures::future::join_all(uploads).await;
+    assert!(statuses.iter().all(|status| *status == StatusCode::OK));
+
+    let mut received = [
+        std::fs::read(server.destination_directory.join("same.bin")).unwrap(),
+        std::fs::read(server.destination_directory.join("same (1).bin")).unwrap(),
+    ];
+    received.sort();
+    let mut expected = [content(0), content(1)];
+    expected.sort();
+    assert!(received == expected);
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn parallel_sessions_with_the_same_name_are_all_kept() {
+    let options = ServerOptions {
+        config: ServerConfig {
+            max_concurrent_sessions: 2,
+            ..Default::default()
+        },
+        ..Default::default()
+    };
+    let server = start_server(53440, options, accept_all).await;
+    let (first_session, first_file_ids) =
+        start_session(server.addr, &["same.bin"], FILE_SIZE).await;
+    let (second_session, second_file_ids) =
+        start_session(server.addr, &["same.bin"], FILE_SIZE).await;
+
+    let statuses = futures::future::join(
+        upload_in_chunks(server.addr, &first_session, &first_file_ids[0], content(0)),
+        upload_in_chunks(
+            server.addr,
+            &second_session,
+            &second_file_ids[0],
+            content(1),
+        ),
+    )
+    .await;
+    assert_eq!(statuses, (StatusCode::OK, StatusCode::OK));
+
+    let mut received = [
+        std::fs::read(server.destination_directory.join("same.bin")).unwrap(),
+        std::fs::read(server.destination_directory.join("same (1).bin")).unwrap(),
+    ];
+    received.sort();
+    let mut expected = [content(0), content(1)];
+    expected.sort();
+    assert!(received == expected);
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn concurrent_upload_of_the_same_file_is_rejected() {
+    let server = start_server(53433, ServerOptions::default(), accept_all).await;
+    let (session, file_ids) =
+        start_session(server.addr, &["once.bin", "other.bin"], FILE_SIZE).await;
+
+    let (first, second) = tokio::join!(
+        upload_in_chunks(server.addr, &session, &file_ids[0], content(0)),
+        async {
+            // let the first upload claim the file
+            tokio::time::sleep(Duration::from_millis(20)).await;
+            upload_in_chunks(server.addr, &session, &file_ids[0], content(1)).await
+        },
+    );
+    assert_eq!(first, StatusCode::OK);
+    assert_eq!(second, StatusCode::CONFLICT);
+
+    let received = std::fs::read(server.destination_directory.join("once.bin")).unwrap();
+    assert!(received == content(0));
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn cancelling_stops_uploads_and_removes_partial_files() {
+    let mut server = start_server(53434, ServerOptions::default(), accept_all).await;
+    let (session, file_ids) =
+        start_session(server.addr, &["first.bin", "second.bin"], FILE_SIZE).await;
+
+    let uploads = file_ids
+        .iter()
+        .enumerate()
+        .map(|(index, file_id)| upload_in_chunks(server.addr, &session, file_id, content(index)));
+    let cancel = async {
+        tokio::time::sleep(Duration::from_millis(30)).await;
+        server.cancel_handle.cancel().await
+    };
+    let (statuses, cancelled_sessions) = tokio::time::timeout(
+        Duration::from_secs(1),
+        futures::future::join(futures::future::join_all(uploads), cancel),
+    )
+    .await
+    .unwrap();
+    assert_eq!(cancelled_sessions, 1);
+    assert!(statuses.iter().all(|status| *status != StatusCode::OK));
+
+    let mut cancelled = false;
+    while let Ok(server_msg) = server.server_rx.try_recv() {
+        cancelled |= matches!(
+            server_msg,
+            ServerMessage::CancelSession(session_id) if session_id == session.session_id
+        );
+    }
+    assert!(cancelled);
+    let files_left = std::fs::read_dir(&server.destination_directory)
+        .unwrap()
+        .count();
+    assert_eq!(files_left, 0);
+    assert_eq!(server.cancel_handle.cancel().await, 0);
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn uploads_larger_than_declared_are_aborted() {
+    let server = start_server(53435, ServerOptions::default(), accept_all).await;
+    let (session, file_ids) = start_session(server.addr, &["large.bin"], FILE_SIZE).await;
+
+    // a sender that never stops, the upload has to be cut off at the declared size
+    let endless = futures::stream::repeat_with(|| Ok::<_, io::Error>(vec![0u8; CHUNK_SIZE]));
+    let file_id = file_ids[0].as_str();
+    let token = &session.files[file_id];
+    let upload = upload(
+        server.addr,
+        &session.session_id,
+        file_id,
+        token,
+        Body::wrap_stream(endless),
+    );
+    let status = tokio::time::timeout(Duration::from_secs(5), upload)
+        .await
+        .unwrap();
+    assert_eq!(status, StatusCode::INTERNAL_SERVER_ERROR);
+
+    let files_left = std::fs::read_dir(&server.destination_directory)
+        .unwrap()
+        .count();
+    assert_eq!(files_left, 0);
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn existing_part_files_are_left_alone() {
+    let server = start_server(53436, ServerOptions::default(), accept_all).await;
+    let (session, file_ids) = start_session(server.addr, &["notes.txt"], FILE_SIZE).await;
+    let users_file = server.destination_directory.join("notes.txt.part");
+    std::fs::write(&users_file, b"not ours").unwrap();
+
+    let status = upload_in_chunks(server.addr, &session, &file_ids[0], content(0)).await;
+    assert_eq!(status, StatusCode::OK);
+
+    assert_eq!(std::fs::read(&users_file).unwrap(), b"not ours");
+    let received = std::fs::read(server.destination_directory.join("notes.txt")).unwrap();
+    assert!(received == content(0));
+    let files_left = std::fs::read_dir(&server.destination_directory)
+        .unwrap()
+        .count();
+    assert_eq!(files_left, 2);
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn overwrite_replaces_existing_files() {
+    let server = start_server_with_existing_file(53437, CollisionPolicy::Overwrite).await;
+    let (session, file_ids) = start_session(server.addr, &["existing.txt"], FILE_SIZE).await;
+
+    let status = upload_in_chunks(server.addr, &session, &file_ids[0], content(0)).await;
+    assert_eq!(status, StatusCode::OK);
+
+    let received = std::fs::read(server.destination_directory.join("existing.txt")).unwrap();
+    assert!(received == content(0));
+    assert_eq!(files_in(&server), 1);
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn skip_leaves_existing_files_alone() {
+    let server = start_server_with_existing_file(53438, CollisionPolicy::Skip).await;
+    let (session, file_ids) =
+        start_session(server.addr, &["existing.txt", "existing.txt"], FILE_SIZE).await;
+
+    let status = upload_in_chunks(server.addr, &session, &file_ids[0], content(0)).await;
+    assert_eq!(status, StatusCode::OK);
+
+    // skipped files are drained, but not past their declared size
+    let endless = futures::stream::repeat_with(|| Ok::<_, io::Error>(vec![0u8; CHUNK_SIZE]));
+    let file_id = file_ids[1].as_str();
+    let token = &session.files[file_id];
+    let upload = upload(
+        server.addr,
+        &session.session_id,
+        file_id,
+        token,
+        Body::wrap_stream(endless),
+    );
+    let status = tokio::time::timeout(Duration::from_secs(5), upload)
+        .await
+        .unwrap();
+    assert_eq!(status, StatusCode::INTERNAL_SERVER_ERROR);
+
+    let kept = std::fs::read(server.destination_directory.join("existing.txt")).unwrap();
+    assert_eq!(kept, USERS_FILE);
+    assert_eq!(files_in(&server), 1);
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn fail_rejects_files_that_exist() {
+    let server = start_server_with_existing_file(53439, CollisionPolicy::Fail).await;
+    let (session, file_ids) = start_session(server.addr, &["existing.txt"], FILE_SIZE).await;
+
+    let status = upload_in_chunks(server.addr, &session, &file_ids[0], content(0)).await;
+    assert_eq!(status, StatusCode::CONFLICT);
+
+    let kept = std::fs::read(server.destination_directory.join("existing.txt")).unwrap();
+    assert_eq!(kept, USERS_FILE);
+    assert_eq!(files_in(&server), 1);
+}
@@ -0,0 +1,73 @@
+mod common;
+
+use std::net::{IpAddr, Ipv6Addr, SocketAddr};
+
+use common::{accept_all, client, start_server, ServerOptions, TestServer};
+use localsend_core::Protocol;
+use uuid::Uuid;
+
+async fn start_ipv6_server(interface_addr: IpAddr, port: u16, protocol: Protocol) -> TestServer {
+    let options = ServerOptions {
+        interface_addr,
+        protocol,
+        ..Default::default()
+    };
+    start_server(port, options, accept_all).await
+}
+
+#[tokio::test]
+async fn info_over_ipv6_loopback() {
+    let server = start_ipv6_server(Ipv6Addr::LOCALHOST.into(), 53421, Protocol::Https).await;
+
+    let device_info = client(Protocol::Https)
+        .fetch_info(server.addr)
+        .await
+        .unwrap();
+    assert_eq!(device_info.alias, "server");
+    assert_eq!(device_info.ip, IpAddr::V6(Ipv6Addr::LOCALHOST));
+    assert_eq!(device_info.socket_addr(), server.addr);
+}
+
+#[tokio::test]
+async fn register_over_ipv6_loopback() {
+    let server = start_ipv6_server(Ipv6Addr::LOCALHOST.into(), 53422, Protocol::Https).await;
+
+    client(Protocol::Https)
+        .register(server.addr, Protocol::Https)
+        .await
+        .unwrap();
+    let peer = server.registry.get("client-fingerprint").unwrap();
+    assert_eq!(peer.device_info.ip, IpAddr::V6(Ipv6Addr::LOCALHOST));
+    assert_eq!(peer.device_info.scope_id, 0);
+}
+
+#[tokio::test]
+async fn send_over_ipv6_loopback() {
+    let server = start_ipv6_server(Ipv6Addr::LOCALHOST.into(), 53423, Protocol::Http).await;
+    let path = std::env::temp_dir().join(format!("{}.txt", Uuid::new_v4()));
+    std::fs::write(&path, b"hello over ipv6").unwrap();
+
+    client(Protocol::Http)
+        .send_files(server.addr, std::slice::from_ref(&path), None)
+        .await
+        .unwrap();
+    let received = server.destination_directory.join(path.file_name().unwrap());
+    assert_eq!(std::fs::read(received).unwrap(), b"hello over ipv6");
+}
+
+#[tokio::test]
+async fn dual_stack_server_accepts_ipv4() {
+    let server = start_ipv6_server(Ipv6Addr::UNSPECIFIED.into(), 53424, Protocol::Http).await;
+    let path = std::env::temp_dir().join(format!("{}.txt", Uuid::new_v4()));
+    std::fs::write(&path, b"hello over ipv4").unwrap();
+
+    // uploads are only authorized for the address that started the session, which has to be
+    // the same whether or not it was mapped to IPv6
+    let addr = SocketAddr::from(([127, 0, 0, 1], server.addr.port()));
+    client(Protocol::Http)
+        .send_files(addr, std::slice::from_ref(&path), None)
+        .await
+        .unwrap();
+    let received = server.destination_directory.join(path.file_name().unwrap());
+    assert_eq!(std::fs::read(received).unwrap(), b"hello over ipv4");
+}
@@ -0,0 +1,96 @@
+mod common;
+
+use std::{
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+    path::Path,
+    sync::Arc,
+};
+
+use common::{accept_all, client, file_to_send, start_server, ServerOptions};
+use localsend_core::{Client, Error, KnownPeers, Protocol};
+use uuid::Uuid;
+
+async fn start_https_server(port: u16, fingerprint: Option<&str>) -> SocketAddr {
+    let options = ServerOptions {
+        protocol: Protocol::Https,
+        fingerprint: fingerprint.map(Into::into),
+        ..Default::default()
+    };
+    start_server(port, options, accept_all).await.addr
+}
+
+fn pinning_client(state_dir: &Path) -> Client {
+    let known_peers = Arc::new(KnownPeers::load(state_dir).unwrap());
+    client(Protocol::Https).with_known_peers(known_peers)
+}
+
+#[tokio::test]
+async fn pinned_address_refuses_another_fingerprint() {
+    let state_dir = std::env::temp_dir().join(Uuid::new_v4().to_string());
+    let path = file_to_send(b"pinned");
+
+    let addr = start_https_server(53451, None).await;
+    pinning_client(&state_dir)
+        .send_files(addr, std::slice::from_ref(&path), None)
+        .await
+        .unwrap();
+    let known_peers = KnownPeers::load(&state_dir).unwrap().list();
+    assert_eq!(known_peers[0].addresses, vec![addr.ip()]);
+
+    // another device on the same address, with a certificate of its own
+    let addr = start_https_server(53452, None).await;
+    let err = pinning_client(&state_dir)
+        .send_files(addr, std::slice::from_ref(&path), None)
+        .await
+        .unwrap_err();
+    assert!(matches!(err, Error::AddressMismatch { .. }), "{}", err);
+}
+
+#[tokio::test]
+async fn certificate_has_to_match_the_fingerprint() {
+    let state_dir = std::env::temp_dir().join(Uuid::new_v4().to_string());
+    let path = file_to_send(b"pinned");
+
+    let addr = start_https_server(53453, Some("not-the-certificate-hash")).await;
+    let err = pinning_client(&state_dir)
+        .send_files(addr, std::slice::from_ref(&path), None)
+        .await
+        .unwrap_err();
+    assert!(matches!(err, Error::FingerprintMismatch { .. }), "{}", err);
+    assert!(KnownPeers::load(&state_dir).unwrap().list().is_empty());
+}
+
+#[tokio::test]
+async fn pinned_peer_is_trusted_at_a_new_address() {
+    let state_dir = std::env::temp_dir().join(Uuid::new_v4().to_string());
+    let path = file_to_send(b"pinned");
+    // reachable at 127.0.0.1 and ::1 with the same certificate, like a device that moved
+    let options = ServerOptions {
+        interface_addr: Ipv6Addr::UNSPECIFIED.into(),
+        protocol: Protocol::Https,
+        ..Default::default()
+    };
+    let port = start_server(53454, options, accept_all).await.addr.port();
+    let old_addr = SocketAddr::from((Ipv4Addr::LOCALHOST, port));
+    let new_addr = SocketAddr::from((Ipv6Addr::LOCALHOST, port));
+
+    for addr in [old_addr, new_addr] {
+        pinning_client(&state_dir)
+            .send_files(addr, std::slice::from_ref(&path), None)
+            .await
+            .unwrap();
+    }
+    let known_peers = KnownPeers::load(&state_dir).unwrap().list();
+    assert_eq!(known_peers.len(), 1);
+    let addresses: Vec<IpAddr> = vec![Ipv4Addr::LOCALHOST.into(), Ipv6Addr::LOCALHOST.into()];
+    assert_eq!(known_peers[0].addresses, addresses);
+
+    // another device claiming the pinned fingerprint can't present its certificate
+    let fingerprint = known_peers[0].fingerprint.clone();
+    let addr = start_https_server(53455, Some(&fingerprint)).await;
+    let err = pinning_client(&state_dir)
+        .send_files(addr, std::slice::from_ref(&path), None)
+        .await
+        .unwrap_err();
+    assert!(matches!(err, Error::FingerprintMismatch { .. }), "{}", err);
+}
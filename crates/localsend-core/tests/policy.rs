@@ -0,0 +1,154 @@
+mod common;
+
+use std::{
+    net::{IpAddr, Ipv4Addr},
+    time::Duration,
+};
+
+use common::{accept_all, client, file_to_send, start_server, ServerOptions};
+use localsend_core::{
+    Client, DeviceInfo, Error, PolicyAction, PolicyRule, Protocol, ReceiveDecision, ReceivePolicy,
+    SendRequest, ServerConfig, SessionError,
+};
+
+fn rule(name: &str, action: PolicyAction) -> PolicyRule {
+    PolicyRule {
+        name: name.into(),
+        action,
+        fingerprints: vec![],
+        aliases: vec![],
+        max_total_size: None,
+        max_files: None,
+        extensions: vec![],
+        file_types: vec![],
+        time_window: None,
+    }
+}
+
+fn trust_client() -> ReceivePolicy {
+    ReceivePolicy {
+        rules: vec![PolicyRule {
+            fingerprints: vec!["client-fingerprint".into()],
+            ..rule("trusted", PolicyAction::Accept)
+        }],
+        ..Default::default()
+    }
+}
+
+fn trusting_server_options() -> ServerOptions {
+    ServerOptions {
+        config: ServerConfig {
+            policy: trust_client(),
+            ..Default::default()
+        },
+        ..Default::default()
+    }
+}
+
+async fn decline_all(_: SendRequest) -> ReceiveDecision {
+    ReceiveDecision::Decline
+}
+
+#[tokio::test]
+async fn accept_rules_only_trust_fingerprints_of_known_peers() {
+    let options = trusting_server_options();
+    let server = start_server(53461, options, decline_all).await;
+    let client = client(Protocol::Http);
+    let path = file_to_send(b"trusted");
+    let files = std::slice::from_ref(&path);
+
+    // anyone can claim the fingerprint
+    assert!(client.send_files(server.addr, files, None).await.is_err());
+
+    client.register(server.addr, Protocol::Http).await.unwrap();
+    client.send_files(server.addr, files, None).await.unwrap();
+    let received = server.destination_directory.join(path.file_name().unwrap());
+    assert_eq!(std::fs::read(received).unwrap(), b"trusted");
+}
+
+#[tokio::test]
+async fn registering_a_known_fingerprint_doesnt_move_it() {
+    let options = trusting_server_options();
+    let server = start_server(53463, options, decline_all).await;
+    // the trusted peer announced itself somewhere else
+    let trusted_ip = IpAddr::from(Ipv4Addr::new(192, 0, 2, 1));
+    server.registry.upsert(DeviceInfo {
+        alias: "client".into(),
+        fingerprint: "client-fingerprint".into(),
+        ip: trusted_ip,
+        ..Default::default()
+    });
+
+    let impostor = client(Protocol::Http);
+    impostor
+        .register(server.addr, Protocol::Http)
+        .await
+        .unwrap();
+    let known_peer = server.registry.get("client-fingerprint").unwrap();
+    assert_eq!(known_peer.device_info.ip, trusted_ip);
+
+    let path = file_to_send(b"impostor");
+    let err = impostor
+        .send_files(server.addr, std::slice::from_ref(&path), None)
+        .await
+        .unwrap_err();
+    assert!(
+        matches!(err, Error::Session(SessionError::Declined)),
+        "{}",
+        err
+    );
+    assert!(!server
+        .destination_directory
+        .join(path.file_name().unwrap())
+        .exists());
+}
+
+#[tokio::test]
+async fn blocked_senders_dont_wait_for_a_slot() {
+    let options = ServerOptions {
+        config: ServerConfig {
+            policy: ReceivePolicy {
+                rules: vec![PolicyRule {
+                    aliases: vec!["blocked".into()],
+                    ..rule("blocklist", PolicyAction::Decline)
+                }],
+                ..Default::default()
+            },
+            queue_size: 1,
+            ..Default::default()
+        },
+        ..Default::default()
+    };
+    // holds on to the only slot by never deciding
+    let undecided = |send_request: SendRequest| async move {
+        tokio::time::sleep(Duration::from_secs(10)).await;
+        accept_all(send_request).await
+    };
+    let server = start_server(53462, options, undecided).await;
+    let path = file_to_send(b"trusted");
+    let files = std::slice::from_ref(&path);
+
+    let waiting = tokio::spawn(async move {
+        client(Protocol::Http)
+            .send_files(server.addr, &[file_to_send(b"trusted")], None)
+            .await
+    });
+    tokio::time::sleep(Duration::from_millis(50)).await;
+
+    let blocked = Client::new("blocked".into(), "blocked-fingerprint".into(), 53317)
+        .unwrap()
+        .with_protocol(Protocol::Http);
+    let err = tokio::time::timeout(
+        Duration::from_secs(1),
+        blocked.send_files(server.addr, files, None),
+    )
+    .await
+    .unwrap()
+    .unwrap_err();
+    assert!(
+        matches!(err, Error::Session(SessionError::Declined)),
+        "{}",
+        err
+    );
+    waiting.abort();
+}
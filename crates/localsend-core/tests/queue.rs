@@ -0,0 +1,160 @@
+mod common;
+
+use std::{
+    net::SocketAddr,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
+
+use common::{accept_all, prepare_upload, start_server, upload, ServerOptions};
+use localsend_core::{PrepareUploadResponse, SendRequest, ServerConfig};
+use reqwest::StatusCode;
+
+const CONTENT: &[u8] = b"queued";
+
+async fn start_queue_server(port: u16, config: ServerConfig) -> SocketAddr {
+    let options = ServerOptions {
+        config,
+        ..Default::default()
+    };
+    start_server(port, options, accept_all).await.addr
+}
+
+async fn offer_file(addr: SocketAddr) -> reqwest::Response {
+    prepare_upload(addr, &["queued.txt"], CONTENT.len()).await.0
+}
+
+// uploads the only file of the session, which ends it
+async fn finish_session(addr: SocketAddr, session: PrepareUploadResponse) {
+    let (file_id, token) = session.files.into_iter().next().unwrap();
+    let status = upload(addr, &session.session_id, &file_id, &token, CONTENT).await;
+    assert_eq!(status, StatusCode::OK);
+}
+
+#[tokio::test]
+async fn without_a_queue_requests_are_rejected() {
+    let addr = start_queue_server(53441, ServerConfig::default()).await;
+
+    let first = offer_file(addr).await;
+    assert_eq!(first.status(), StatusCode::OK);
+    assert_eq!(offer_file(addr).await.status(), StatusCode::CONFLICT);
+}
+
+#[tokio::test]
+async fn queued_requests_are_decided_on_in_order() {
+    let config = ServerConfig {
+        queue_size: 2,
+        ..Default::default()
+    };
+    let addr = start_queue_server(53442, config).await;
+
+    let first = offer_file(addr).await;
+    assert_eq!(first.status(), StatusCode::OK);
+    let second = tokio::spawn(offer_file(addr));
+    tokio::time::sleep(Duration::from_millis(50)).await;
+    let third = tokio::spawn(offer_file(addr));
+    tokio::time::sleep(Duration::from_millis(50)).await;
+    // the queue is full
+    assert_eq!(offer_file(addr).await.status(), StatusCode::CONFLICT);
+    assert!(!second.is_finished() && !third.is_finished());
+
+    finish_session(addr, first.json().await.unwrap()).await;
+    let second = second.await.unwrap();
+    assert_eq!(second.status(), StatusCode::OK);
+    assert!(!third.is_finished());
+
+    finish_session(addr, second.json().await.unwrap()).await;
+    assert_eq!(third.await.unwrap().status(), StatusCode::OK);
+}
+
+#[tokio::test]
+async fn queued_requests_time_out() {
+    let config = ServerConfig {
+        queue_size: 1,
+        queue_timeout: Duration::from_millis(200),
+        ..Default::default()
+    };
+    let addr = start_queue_server(53443, config).await;
+
+    assert_eq!(offer_file(addr).await.status(), StatusCode::OK);
+    assert_eq!(offer_file(addr).await.status(), StatusCode::CONFLICT);
+    // the request that timed out gave up its place in the queue
+    let queued = tokio::spawn(offer_file(addr));
+    tokio::time::sleep(Duration::from_millis(50)).await;
+    assert!(!queued.is_finished());
+}
+
+#[tokio::test]
+async fn sessions_run_concurrently_up_to_the_limit() {
+    let config = ServerConfig {
+        max_concurrent_sessions: 2,
+        ..Default::default()
+    };
+    let addr = start_queue_server(53444, config).await;
+
+    let first = offer_file(addr).await;
+    assert_eq!(first.status(), StatusCode::OK);
+    let second = offer_file(addr).await;
+    assert_eq!(second.status(), StatusCode::OK);
+    assert_eq!(offer_file(addr).await.status(), StatusCode::CONFLICT);
+
+    finish_session(addr, second.json().await.unwrap()).await;
+    assert_eq!(offer_file(addr).await.status(), StatusCode::OK);
+    finish_session(addr, first.json().await.unwrap()).await;
+}
+
+#[tokio::test]
+async fn requests_without_files_to_send_free_their_slot() {
+    let addr = start_queue_server(53445, ServerConfig::default()).await;
+
+    let (response, _) = prepare_upload(addr, &[], 0).await;
+    assert_eq!(response.status(), StatusCode::NO_CONTENT);
+    assert_eq!(offer_file(addr).await.status(), StatusCode::OK);
+}
+
+#[tokio::test]
+async fn senders_hanging_up_during_the_decision_free_their_slot() {
+    // never decides on the first request, accepts the ones after it
+    let requests = Arc::new(AtomicUsize::new(0));
+    let receive_handler = move |send_request: SendRequest| {
+        let first = requests.fetch_add(1, Ordering::SeqCst) == 0;
+        async move {
+            if first {
+                futures::future::pending::<()>().await;
+            }
+            accept_all(send_request).await
+        }
+    };
+    let addr = start_server(53446, ServerOptions::default(), receive_handler)
+        .await
+        .addr;
+
+    let hung_up = tokio::time::timeout(Duration::from_millis(100), offer_file(addr)).await;
+    assert!(hung_up.is_err());
+    tokio::time::sleep(Duration::from_millis(50)).await;
+    assert_eq!(offer_file(addr).await.status(), StatusCode::OK);
+}
+
+#[tokio::test]
+async fn idle_sessions_time_out_and_free_their_slot() {
+    let config = ServerConfig {
+        session_timeout: Duration::from_millis(200),
+        ..Default::default()
+    };
+    let addr = start_queue_server(53447, config).await;
+
+    let idle = offer_file(addr).await;
+    assert_eq!(idle.status(), StatusCode::OK);
+    assert_eq!(offer_file(addr).await.status(), StatusCode::CONFLICT);
+
+    tokio::time::sleep(Duration::from_millis(400)).await;
+    assert_eq!(offer_file(addr).await.status(), StatusCode::OK);
+    // the idle session is gone, its files can't be uploaded anymore
+    let session: PrepareUploadResponse = idle.json().await.unwrap();
+    let (file_id, token) = session.files.into_iter().next().unwrap();
+    let status = upload(addr, &session.session_id, &file_id, &token, CONTENT).await;
+    assert_eq!(status, StatusCode::FORBIDDEN);
+}
@@ -1,26 +1,153 @@
-use std::{collections::HashMap, fmt::Write, io, net::Ipv4Addr, time::Duration};
+use std::{
+    collections::HashMap,
+    fmt::Write,
+    io,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+    path::PathBuf,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, PoisonError,
+    },
+    time::Duration,
+};
 
+use clap::{Parser, Subcommand};
 use console::style;
 use dialoguer::{theme::ColorfulTheme, MultiSelect};
+use futures::StreamExt;
 use indicatif::{MultiProgress, ProgressBar, ProgressState, ProgressStyle};
+use time::UtcOffset;
 use tokio::{runtime, sync::mpsc};
 use tracing::{debug, info};
 use tracing_subscriber::{filter::EnvFilter, FmtSubscriber};
 
-use localsend_core::{ClientMessage, DeviceScanner, FileInfo, Server, ServerMessage};
+use localsend_core::{
+    CancelHandle, Client, CollisionPolicy, DeviceEvent, DeviceScanner, Error, FileInfo,
+    FileOutcome, Identity, KnownPeers, NetworkWatcher, PolicyAction, Protocol, ReceiveDecision,
+    ReceivePolicy, SendMessage, SendRequest, Server, ServerConfig, ServerMessage, SweepConfig,
+};
 
 const ALIAS: &str = "rustsend";
 const INTERFACE_ADDR: Ipv4Addr = Ipv4Addr::new(0, 0, 0, 0);
 const MULTICAST_ADDR: Ipv4Addr = Ipv4Addr::new(224, 0, 0, 167);
+const MULTICAST_ADDR_V6: Ipv6Addr = Ipv6Addr::new(0xff02, 0, 0, 0, 0, 0, 0, 0x167);
+// also accepts IPv4 connections
+const SERVER_ADDR: IpAddr = IpAddr::V6(Ipv6Addr::UNSPECIFIED);
 const MULTICAST_PORT: u16 = 53317;
 
+#[derive(Parser)]
+#[command(version, about)]
+struct Cli {
+    #[command(subcommand)]
+    command: Option<Command>,
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// Receive files from other devices (default)
+    Receive {
+        /// Directory to save received files to, defaults to the downloads directory
+        #[arg(short, long)]
+        destination: Option<PathBuf>,
+        /// What to do when a file with the same name exists: rename, overwrite, skip or fail
+        #[arg(long, default_value = "rename")]
+        on_conflict: CollisionPolicy,
+        /// Keep partially received files of failed or cancelled transfers
+        #[arg(long)]
+        keep_partial: bool,
+        /// Serve plain http instead of https, for devices with encryption turned off
+        #[arg(long)]
+        http: bool,
+        /// Device to add by address, for networks discovery doesn't reach. Can be repeated
+        #[arg(long = "peer", value_name = "HOST[:PORT]")]
+        peers: Vec<String>,
+        /// JSON file with rules that accept or decline send requests without asking
+        #[arg(long)]
+        policy: Option<PathBuf>,
+        /// Send requests to hold on to while receiving, instead of turning them away
+        #[arg(long, value_name = "SIZE", default_value_t = 0)]
+        queue: usize,
+    },
+    /// List devices on the network as they are discovered
+    Devices {
+        /// Also probe every host on the local subnets, for networks that block multicast
+        #[arg(long)]
+        sweep: bool,
+        /// Device to add by address, for networks discovery doesn't reach. Can be repeated
+        #[arg(long = "peer", value_name = "HOST[:PORT]")]
+        peers: Vec<String>,
+    },
+    /// Send files to a device
+    Send {
+        /// Address of the receiving device, `ip` or `ip:port`
+        peer: String,
+        /// Files to send
+        #[arg(required = true)]
+        files: Vec<PathBuf>,
+        /// Send over plain http, for devices with encryption turned off
+        #[arg(long)]
+        http: bool,
+    },
+    /// Manage the certificates pinned for known devices
+    Peers {
+        #[command(subcommand)]
+        command: PeersCommand,
+    },
+}
+
+#[derive(Subcommand)]
+enum PeersCommand {
+    /// List known devices and their pinned certificates
+    List,
+    /// Forget a device so its certificate is trusted on first use again
+    Forget {
+        /// Fingerprint or alias of the device
+        fingerprint_or_alias: String,
+    },
+}
+
 struct State {
     multi_progress: MultiProgress,
     files: HashMap<String, FileInfo>,
     progress_map: HashMap<String, ProgressBar>,
 }
 
+// set up when the user accepts a send request, shared with the receive handler
+type ClientState = Arc<std::sync::Mutex<Option<State>>>;
+
+// held by the prompt reading the terminal, so prompts of concurrent requests don't mix
+static PROMPT: std::sync::Mutex<()> = std::sync::Mutex::new(());
+
+// the blocking prompt can't be interrupted, when the server stops waiting for a decision this
+// tells the user the request is gone and has the prompt's answer ignored
+struct PromptExpiry {
+    alias: String,
+    expired: Arc<AtomicBool>,
+    prompting: Arc<AtomicBool>,
+    decided: bool,
+}
+
+impl Drop for PromptExpiry {
+    fn drop(&mut self) {
+        if self.decided {
+            return;
+        }
+        self.expired.store(true, Ordering::SeqCst);
+        let mut msg = format!(
+            "\nThe request of {} was declined, it wasn't answered in time.",
+            style(&self.alias).bold().magenta()
+        );
+        if self.prompting.load(Ordering::SeqCst) {
+            msg.push_str(" Press enter to dismiss its prompt.");
+        }
+        println!("{}", style(msg).yellow());
+    }
+}
+
 fn main() {
+    let cli = Cli::parse();
+    // only works as long as there is a single thread
+    let utc_offset = UtcOffset::current_local_offset().unwrap_or(UtcOffset::UTC);
     console_subscriber::init();
     // init_tracing_logger();
     // TODO: should i use new_current_thread or new_multi_thread?
@@ -29,101 +156,153 @@ fn main() {
         .build()
         .unwrap();
 
-    let _ = runtime.block_on(async_main());
+    if let Err(err) = runtime.block_on(async_main(cli, utc_offset)) {
+        println!("{}", style(format!("Error: {}", err)).red());
+    }
     // https://stackoverflow.com/questions/73528236/how-to-terminate-a-blocking-tokio-task
     // start_device_scanner blocks exit, so set timeout or use async_std crate (adds to the binary size and compile time)
     runtime.shutdown_timeout(Duration::from_millis(1));
 }
 
+// asks the user which of the offered files to receive, and sets up their progress bars
+async fn decide(send_request: SendRequest, client_state: ClientState) -> ReceiveDecision {
+    let alias = send_request.device_info.alias.clone();
+    let mut files = send_request.files.into_iter().collect::<Vec<_>>();
+    files.sort_by(|(_, a), (_, b)| a.file_name.cmp(&b.file_name));
+    let mut expiry = PromptExpiry {
+        alias: alias.clone(),
+        expired: Arc::default(),
+        prompting: Arc::default(),
+        decided: false,
+    };
+    let (expired, prompting) = (expiry.expired.clone(), expiry.prompting.clone());
+
+    // the prompt blocks, so it runs off the runtime to keep the server going meanwhile
+    let prompt = tokio::task::spawn_blocking(move || {
+        let _prompt = PROMPT.lock().unwrap_or_else(PoisonError::into_inner);
+        if expired.load(Ordering::SeqCst) {
+            return (files, Ok(vec![]));
+        }
+        prompting.store(true, Ordering::SeqCst);
+        println!(
+            "{} wants to send you the following files:\n",
+            style(alias).bold().magenta()
+        );
+        let selections = MultiSelect::with_theme(&ColorfulTheme::default())
+            .with_prompt("Select the files you want to receive")
+            .items(
+                &files
+                    .iter()
+                    .map(|(_, file_info)| file_info.file_name.as_str())
+                    .collect::<Vec<&str>>(),
+            )
+            .defaults(vec![true; files.len()].as_slice())
+            .interact();
+        (files, selections)
+    })
+    .await;
+    expiry.decided = true;
+    let (files, selections) = match prompt {
+        Ok((files, Ok(selections))) if !selections.is_empty() => (files, selections),
+        _ => return ReceiveDecision::Decline,
+    };
+
+    let file_ids = selections
+        .into_iter()
+        .map(|idx| files[idx].0.clone())
+        .collect::<Vec<_>>();
+    *client_state.lock().unwrap() = Some(receive_state(files.into_iter().collect(), &file_ids));
+    ReceiveDecision::Accept {
+        file_ids,
+        destination: None,
+    }
+}
+
+// progress bars for the accepted files of a send request
+fn receive_state(files: HashMap<String, FileInfo>, file_ids: &[String]) -> State {
+    let multi_progress = MultiProgress::new();
+    let progress_map = file_ids
+        .iter()
+        .filter_map(|file_id| {
+            let file_info = files.get(file_id)?;
+            let pb = multi_progress.add(ProgressBar::new(file_info.size as u64));
+            pb.set_style(progress_style());
+            pb.set_message(file_info.file_name.clone());
+            Some((file_id.clone(), pb))
+        })
+        .collect::<HashMap<String, ProgressBar>>();
+
+    State {
+        files,
+        multi_progress,
+        progress_map,
+    }
+}
+
 async fn handle_server_msgs(
     mut server_rx: localsend_core::protos::Receiver<ServerMessage>,
-    client_tx: localsend_core::protos::Sender<ClientMessage>,
+    client_state: ClientState,
 ) {
-    // TODO: set this back to None when we are done with a session
-    let mut client_state: Option<State> = None;
-
     while let Some(server_message) = server_rx.recv().await {
         debug!("{:?}", &server_message);
+        let mut client_state = client_state.lock().unwrap();
         match server_message {
-            ServerMessage::SendRequest(send_request) => {
-                println!(
-                    "{} wants to send you the following files:\n",
-                    style(send_request.device_info.alias).bold().magenta()
-                );
-
-                let selections = MultiSelect::with_theme(&ColorfulTheme::default())
-                    .with_prompt("Select the files you want to receive")
-                    .items(
-                        &send_request
-                            .files
-                            .values()
-                            .map(|file_info| file_info.file_name.as_str())
-                            .collect::<Vec<&str>>(),
-                    )
-                    .defaults(vec![true; send_request.files.len()].as_slice())
-                    .interact()
-                    .unwrap();
-
-                if selections.is_empty() {
-                    let _ = client_tx.send(ClientMessage::Decline);
-                } else {
-                    let file_ids = send_request
-                        .files
-                        .keys()
-                        .map(|file_id| file_id.as_str())
-                        .collect::<Vec<&str>>();
-
-                    let selected_file_ids = selections
-                        .into_iter()
-                        .map(|idx| String::from(file_ids[idx]))
-                        .collect::<Vec<_>>();
-                    let _ = client_tx.send(ClientMessage::Allow(selected_file_ids));
-
-                    let multi_progress = MultiProgress::new();
-                    let progress_map = send_request
-                            .files
-                            .clone()
-                            .into_iter()
-                            .map(|(file_id, file_info)| {
-                                // TODO(notjedi): change length ot size of file
-                                let pb =
-                                    multi_progress.add(ProgressBar::new(file_info.size as u64));
-
-                                pb.set_style(ProgressStyle::with_template("{spinner:.green} [{msg}] [{wide_bar:.cyan/blue}] {bytes}/{total_bytes} ({eta})")
-                                    .unwrap()
-                                    .with_key("eta", |state: &ProgressState, w: &mut dyn Write| write!(w, "{:.1}s", state.eta().as_secs_f64()).unwrap())
-                                    .progress_chars("#>-"));
-
-                                pb.set_message(file_info.file_name);
-                                (file_id, pb)
-                            })
-                            .collect::<HashMap<String, ProgressBar>>();
-
-                    client_state = Some(State {
-                        files: send_request.files,
-                        multi_progress,
-                        progress_map,
-                    });
+            ServerMessage::PolicyDecision((send_request, policy_match)) => {
+                let alias = style(&send_request.device_info.alias).bold().magenta();
+                match policy_match.action {
+                    PolicyAction::Accept => {
+                        println!(
+                            "Accepted {} file(s) from {} (rule {})",
+                            send_request.files.len(),
+                            alias,
+                            policy_match.rule
+                        );
+                        let file_ids = send_request.files.keys().cloned().collect::<Vec<_>>();
+                        *client_state = Some(receive_state(send_request.files, &file_ids));
+                    }
+                    PolicyAction::Decline => println!(
+                        "Declined {} file(s) from {} (rule {})",
+                        send_request.files.len(),
+                        alias,
+                        policy_match.rule
+                    ),
                 }
             }
-            ServerMessage::SendFileRequest((file_id, size)) => match client_state.as_ref() {
+            ServerMessage::SendFileRequest((_, file_id, size)) => match client_state.as_ref() {
                 Some(state) => {
                     state.progress_map[&file_id].inc(size as u64);
-                    if state.progress_map[&file_id].position()
-                        == (state.files[&file_id].size as u64)
-                    {
-                        state.progress_map[&file_id].finish_and_clear();
-                        state
-                            .multi_progress
-                            .println(format!("Received {}", state.files[&file_id].file_name))
-                            .unwrap();
-                    }
                 }
                 None => {
                     info!("client_state is None. this shouldn't be happening as this block is unreachable.")
                 }
             },
-            ServerMessage::CancelSession => match client_state.as_ref() {
+            ServerMessage::FileReceived((_, file_id, outcome)) => match client_state.as_ref() {
+                Some(state) => {
+                    let file_name = &state.files[&file_id].file_name;
+                    let message = match outcome {
+                        FileOutcome::Saved(_) => format!("Received {}", file_name),
+                        FileOutcome::Renamed(path) => {
+                            format!("Received {} as {}", file_name, path.display())
+                        }
+                        FileOutcome::Overwritten(path) => {
+                            format!("Received {}, overwrote {}", file_name, path.display())
+                        }
+                        FileOutcome::Skipped => {
+                            format!("Skipped {}, file already exists", file_name)
+                        }
+                        FileOutcome::Failed(err) => {
+                            format!("{} finished with error: {}", file_name, err)
+                        }
+                    };
+                    state.progress_map[&file_id].finish_and_clear();
+                    state.multi_progress.println(message).unwrap();
+                }
+                None => {
+                    info!("client_state is None. this shouldn't be happening as this block is unreachable.")
+                }
+            },
+            // only one session is received at a time, so the ids aren't needed to tell them apart
+            ServerMessage::CancelSession(_) => match client_state.as_ref() {
                 // TODO(notjedi): handle cancel request when in send request phase
                 Some(state) => {
                     for (file_id, pb) in &state.progress_map {
@@ -132,13 +311,13 @@ async fn handle_server_msgs(
                             state
                                 .multi_progress
                                 .println(format!(
-                                    "{} finished with error",
+                                    "{} was cancelled",
                                     state.files[file_id.as_str()].file_name
                                 ))
                                 .unwrap();
                         }
                     }
-                    client_state = None;
+                    *client_state = None;
                 }
                 None => {
                     info!("client_state is None. this shouldn't be happening as this block is unreachable.")
@@ -148,31 +327,309 @@ async fn handle_server_msgs(
     }
 }
 
-async fn start_device_scanner() {
-    let mut device_scanner = DeviceScanner::new(
+// Ctrl-C cancels the transfer in progress, without one it quits like it normally would
+async fn cancel_on_ctrl_c(cancel_handle: CancelHandle, client_state: ClientState) {
+    while tokio::signal::ctrl_c().await.is_ok() {
+        let receiving = client_state.lock().unwrap().as_ref().is_some_and(|state| {
+            state
+                .progress_map
+                .values()
+                .any(|progress_bar| !progress_bar.is_finished())
+        });
+        if !receiving || cancel_handle.cancel().await == 0 {
+            std::process::exit(130);
+        }
+    }
+}
+
+async fn start_device_scanner(mut device_scanner: DeviceScanner) {
+    if let Err(err) = device_scanner.listen_and_announce_multicast().await {
+        println!(
+            "{}",
+            style(format!("Device discovery stopped: {}", err)).red()
+        );
+    }
+}
+
+async fn list_devices(sweep: bool, peers: Vec<String>) -> Result<(), Error> {
+    let identity = Identity::load_or_generate(&Identity::default_state_dir())?;
+    let network_watcher = NetworkWatcher::default();
+    let device_scanner = DeviceScanner::new(
         ALIAS.to_string(),
+        identity.fingerprint().to_string(),
         INTERFACE_ADDR,
         MULTICAST_ADDR,
         MULTICAST_PORT,
     )
-    .await;
-    device_scanner.listen_and_announce_multicast().await;
+    .await?
+    .with_ipv6_multicast(MULTICAST_ADDR_V6)
+    .with_network_watcher(network_watcher.clone());
+    tokio::spawn(network_watcher.run());
+    for interface in device_scanner.interfaces() {
+        println!(
+            "{}",
+            style(format!(
+                "Discovering on {} ({})",
+                interface.name, interface.addr
+            ))
+            .dim()
+        );
+    }
+    let mut device_events = Box::pin(device_scanner.registry().events());
+    if sweep {
+        let subnet_sweeper = device_scanner.subnet_sweeper(SweepConfig::default())?;
+        tokio::spawn(subnet_sweeper.run());
+    }
+    if !peers.is_empty() {
+        tokio::spawn(device_scanner.static_peers(peers)?.run());
+    }
+    tokio::spawn(start_device_scanner(device_scanner));
+
+    while let Some(device_event) = device_events.next().await {
+        match device_event {
+            DeviceEvent::Discovered(peer) => println!(
+                "{} {} ({}){}",
+                style("+").green(),
+                style(&peer.device_info.alias).bold(),
+                peer.device_info.socket_addr(),
+                if peer.is_static {
+                    style(" static").dim().to_string()
+                } else {
+                    String::new()
+                }
+            ),
+            DeviceEvent::Updated(peer) => println!(
+                "{} {} ({})",
+                style("~").yellow(),
+                style(&peer.device_info.alias).bold(),
+                peer.device_info.socket_addr()
+            ),
+            DeviceEvent::Lost(peer) => println!(
+                "{} {}",
+                style("-").red(),
+                style(&peer.device_info.alias).bold()
+            ),
+        }
+    }
+    Ok(())
 }
 
-async fn async_main() -> Result<(), io::Error> {
-    // spawn task to listen and announce multicast messages
-    tokio::spawn(start_device_scanner());
+fn progress_style() -> ProgressStyle {
+    ProgressStyle::with_template(
+        "{spinner:.green} [{msg}] [{wide_bar:.cyan/blue}] {bytes}/{total_bytes} ({eta})",
+    )
+    .unwrap()
+    .with_key("eta", |state: &ProgressState, w: &mut dyn Write| {
+        write!(w, "{:.1}s", state.eta().as_secs_f64()).unwrap()
+    })
+    .progress_chars("#>-")
+}
+
+fn protocol(http: bool) -> Protocol {
+    if http {
+        Protocol::Http
+    } else {
+        Protocol::Https
+    }
+}
+
+fn parse_peer_addr(peer: &str) -> Option<SocketAddr> {
+    peer.parse::<SocketAddr>().ok().or_else(|| {
+        peer.parse::<std::net::IpAddr>()
+            .ok()
+            .map(|ip| SocketAddr::new(ip, MULTICAST_PORT))
+    })
+}
+
+async fn handle_send_msgs(mut client_rx: localsend_core::protos::Receiver<SendMessage>) {
+    let multi_progress = MultiProgress::new();
+    let mut files: HashMap<String, FileInfo> = HashMap::new();
+    let mut progress_map: HashMap<String, ProgressBar> = HashMap::new();
+
+    while let Some(send_message) = client_rx.recv().await {
+        debug!("{:?}", &send_message);
+        match send_message {
+            SendMessage::Accepted(accepted_files) => {
+                for file_info in accepted_files {
+                    let pb = multi_progress.add(ProgressBar::new(file_info.size as u64));
+                    pb.set_style(progress_style());
+                    pb.set_message(file_info.file_name.clone());
+                    progress_map.insert(file_info.id.clone(), pb);
+                    files.insert(file_info.id.clone(), file_info);
+                }
+            }
+            SendMessage::SendFileProgress((file_id, size)) => {
+                if let Some(pb) = progress_map.get(&file_id) {
+                    pb.inc(size as u64);
+                }
+            }
+            SendMessage::FileFinished(file_id) => {
+                if let Some(pb) = progress_map.get(&file_id) {
+                    pb.finish_and_clear();
+                    multi_progress
+                        .println(format!("Sent {}", files[&file_id].file_name))
+                        .unwrap();
+                }
+            }
+            SendMessage::FileFailed(file_id) => {
+                if let Some(pb) = progress_map.get(&file_id) {
+                    pb.finish_and_clear();
+                    multi_progress
+                        .println(format!("{} finished with error", files[&file_id].file_name))
+                        .unwrap();
+                }
+            }
+        }
+    }
+}
+
+async fn send_files(peer: String, files: Vec<PathBuf>, protocol: Protocol) -> Result<(), Error> {
+    let addr = parse_peer_addr(&peer).ok_or_else(|| {
+        io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("invalid peer address {}", peer),
+        )
+    })?;
 
-    let (server_tx, server_rx) = mpsc::unbounded_channel();
     let (client_tx, client_rx) = mpsc::unbounded_channel();
+    let progress_task = tokio::spawn(handle_send_msgs(client_rx));
 
-    tokio::spawn(handle_server_msgs(server_rx, client_tx));
+    let state_dir = Identity::default_state_dir();
+    let identity = Identity::load_or_generate(&state_dir)?;
+    let known_peers = Arc::new(KnownPeers::load(&state_dir)?);
+    let client = Client::new(
+        ALIAS.to_string(),
+        identity.fingerprint().to_string(),
+        MULTICAST_PORT,
+    )?
+    .with_known_peers(known_peers)
+    .with_protocol(protocol);
+    let result = client.send_files(addr, &files, Some(client_tx)).await;
 
-    let server = Server::new(INTERFACE_ADDR, MULTICAST_PORT);
-    server.start_server(server_tx, client_rx).await;
+    let _ = progress_task.await;
+    match result {
+        Ok(()) => {}
+        Err(err @ (Error::AddressMismatch { .. } | Error::FingerprintMismatch { .. })) => {
+            println!(
+                "{}",
+                style("WARNING: DEVICE IDENTITY HAS CHANGED!").red().bold()
+            );
+            println!("{}", style(err).red());
+            println!(
+                "Refusing to send files. If the device was reinstalled or replaced, run `peers \
+                 forget` to trust its new certificate."
+            );
+        }
+        Err(err) => println!("{}", style(format!("Failed to send files: {}", err)).red()),
+    }
+    Ok(())
+}
+
+fn manage_peers(command: PeersCommand) -> Result<(), Error> {
+    let known_peers = KnownPeers::load(&Identity::default_state_dir())?;
+    match command {
+        PeersCommand::List => {
+            let mut peers = known_peers.list();
+            peers.sort_by(|a, b| a.alias.cmp(&b.alias));
+            for peer in peers {
+                let addresses = peer
+                    .addresses
+                    .iter()
+                    .map(IpAddr::to_string)
+                    .collect::<Vec<_>>()
+                    .join(", ");
+                println!(
+                    "{} {} {}",
+                    style(&peer.alias).bold(),
+                    peer.fingerprint,
+                    style(addresses).dim()
+                );
+            }
+        }
+        PeersCommand::Forget {
+            fingerprint_or_alias,
+        } => match known_peers.forget(&fingerprint_or_alias)? {
+            Some(peer) => println!("Forgot {} ({})", peer.alias, peer.fingerprint),
+            None => println!(
+                "{}",
+                style(format!("No known device {}", fingerprint_or_alias)).red()
+            ),
+        },
+    }
     Ok(())
 }
 
+async fn async_main(cli: Cli, utc_offset: UtcOffset) -> Result<(), Error> {
+    let mut config = ServerConfig::default();
+    let mut static_peers = vec![];
+    match cli.command {
+        Some(Command::Send { peer, files, http }) => {
+            return send_files(peer, files, protocol(http)).await
+        }
+        Some(Command::Devices { sweep, peers }) => return list_devices(sweep, peers).await,
+        Some(Command::Peers { command }) => return manage_peers(command),
+        Some(Command::Receive {
+            destination,
+            on_conflict,
+            keep_partial,
+            http,
+            peers,
+            policy,
+            queue,
+        }) => {
+            if let Some(policy) = policy {
+                config.policy = ReceivePolicy::load(&policy)?.with_utc_offset(utc_offset);
+            }
+            if let Some(destination) = destination {
+                config.destination_directory = destination;
+            }
+            config.collision_policy = on_conflict;
+            config.keep_partial_files = keep_partial;
+            config.protocol = protocol(http);
+            config.queue_size = queue;
+            static_peers = peers;
+        }
+        None => {}
+    }
+
+    // spawn task to listen and announce multicast messages
+    let identity = Identity::load_or_generate(&Identity::default_state_dir())?;
+    let network_watcher = NetworkWatcher::default();
+    let device_scanner = DeviceScanner::new(
+        ALIAS.to_string(),
+        identity.fingerprint().to_string(),
+        INTERFACE_ADDR,
+        MULTICAST_ADDR,
+        MULTICAST_PORT,
+    )
+    .await?
+    .with_ipv6_multicast(MULTICAST_ADDR_V6)
+    .with_protocol(config.protocol)
+    .with_network_watcher(network_watcher.clone());
+    tokio::spawn(network_watcher.clone().run());
+    let this_device = device_scanner.this_device().clone();
+    let registry = device_scanner.registry();
+    if !static_peers.is_empty() {
+        tokio::spawn(device_scanner.static_peers(static_peers)?.run());
+    }
+    tokio::spawn(start_device_scanner(device_scanner));
+
+    let (server_tx, server_rx) = mpsc::unbounded_channel();
+    let client_state = ClientState::default();
+    tokio::spawn(handle_server_msgs(server_rx, client_state.clone()));
+
+    let server = Server::new(this_device, identity, SERVER_ADDR, MULTICAST_PORT, config)
+        .with_registry(registry)
+        .with_network_watcher(network_watcher);
+    tokio::spawn(cancel_on_ctrl_c(
+        server.cancel_handle(),
+        client_state.clone(),
+    ));
+    let receive_handler = move |send_request| decide(send_request, client_state.clone());
+    server.start_server(server_tx, receive_handler).await
+}
+
+#[allow(dead_code)]
 fn init_tracing_logger() {
     let mut subscriber_builder = FmtSubscriber::builder()
         .with_env_filter(